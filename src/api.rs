@@ -1,11 +1,83 @@
-use crate::Follows;
+use crate::{encoding::Encoding, Follows};
 use egg_mode::{entities, tweet, user};
-use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde::{
+    ser::{Serialize, SerializeMap, SerializeSeq, Serializer},
+    Deserialize,
+};
+use std::time::Duration;
+
+// Chosen by the client to tell the replies to its messages apart, echoed back as is
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    String(String),
+}
+
+// A `ClientMessage` along with its optional id, as in `{"type": "exit", "id": 1}`
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ClientRequest {
+    #[serde(default)]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+impl ClientRequest {
+    // Decodes a frame, the id is kept in the error whenever it could be read
+    pub fn parse(encoding: Encoding, data: &[u8]) -> Result<Self, InvalidRequest> {
+        let value = encoding.decode(data).map_err(|message| InvalidRequest {
+            id: None,
+            code: if encoding == Encoding::Json {
+                ErrorCode::InvalidJson
+            } else {
+                ErrorCode::InvalidEncoding
+            },
+            message,
+        })?;
+
+        let id = id_of(&value);
+
+        Self::deserialize(value).map_err(|error| {
+            let message = error.to_string();
+            InvalidRequest {
+                id,
+                // serde doesn't tell the errors apart other than by their message
+                code: if message.starts_with("unknown variant") {
+                    ErrorCode::UnknownType
+                } else {
+                    ErrorCode::InvalidMessage
+                },
+                message,
+            }
+        })
+    }
+
+    // Only reads the id, for replying to messages that aren't otherwise looked at
+    pub fn peek_id(encoding: Encoding, data: &[u8]) -> Option<RequestId> {
+        id_of(&encoding.decode(data).ok()?)
+    }
+}
+
+fn id_of(value: &serde_json::Value) -> Option<RequestId> {
+    value
+        .get("id")
+        .and_then(|id| RequestId::deserialize(id).ok())
+}
+
+#[derive(Debug)]
+pub struct InvalidRequest {
+    pub id: Option<RequestId>,
+    pub code: ErrorCode,
+    pub message: String,
+}
 
 // Stuff that the Client sends over websocket
 #[derive(Clone, Debug, serde::Deserialize)]
 #[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub enum ClientMessage {
+    // First message of a connection, if the token wasn't given during the handshake
+    Auth(String),
     SetSubscriptions(Follows),
     InsertSubscriptions(Follows),
     RemoveSubscriptions(Follows),
@@ -13,6 +85,19 @@ pub enum ClientMessage {
     Exit,
 }
 
+// A `ServerMessage` in reply to a client message, which echoes its id
+#[derive(Debug, serde::Serialize)]
+pub struct Reply<'a> {
+    #[serde(flatten)]
+    pub message: ServerMessage<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a RequestId>,
+    // The follows that were over the follow limits, on `too_many_follows` errors and on the
+    // `ack_subscriptions` that starts an event stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refused: Option<&'a Follows>,
+}
+
 // Stuff that the Server sends over websocket
 #[derive(Debug, serde::Serialize)]
 #[serde(tag = "type", content = "data", rename_all = "snake_case")]
@@ -20,8 +105,71 @@ pub enum ServerMessage<'a> {
     // Sent after Set/Insert/Remove Subscriptions
     AckSubscriptions(&'a Follows),
     Tweet(SerializeWrapper<&'a tweet::Tweet>),
-    // Sent when the client's text frame could not be decoded to a `ClientMessage`
-    ProtocolError(&'a str),
+    // Sent when the client's message could not be decoded or was refused
+    ProtocolError(ProtocolError<'a>),
+    // Sent on connect and whenever the state of the twitter stream changes
+    UpstreamStatus(&'a UpstreamStatus),
+    // Sent on connect when sessions are enabled, the token resumes the session after a disconnect
+    Session(Session<'a>),
+    // Sent before the next tweet when tweets were dropped because the client was too slow,
+    // only with the `notify` slow client policy
+    Dropped { count: u64 },
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ProtocolError<'a> {
+    pub code: ErrorCode,
+    // Meant for humans, may change at any time
+    pub message: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The text frame isn't JSON
+    InvalidJson,
+    // The binary frame isn't valid in the encoding negotiated during the handshake
+    InvalidEncoding,
+    // The message's type isn't one of `ClientMessage`
+    UnknownType,
+    // The message's type is known, but its data or other fields don't fit it
+    InvalidMessage,
+    // The client's role doesn't allow the message
+    Unauthorized,
+    // The client sent too many messages, the message was ignored
+    RateLimited,
+    // Some of the requested follows are over the follow limits and were refused
+    TooManyFollows,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Session<'a> {
+    pub token: &'a str,
+    // Whether the follows and missed tweets of a previous connection were picked back up
+    pub resumed: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    pub grace_period: Duration,
+}
+
+// State of the twitter stream, as published by `twitter::supervisor`
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum UpstreamStatus {
+    // A stream is being opened, no message has been received from it yet
+    Connecting,
+    Connected,
+    // The stream went down, a restart is scheduled
+    BackingOff {
+        #[serde(serialize_with = "serialize_secs")]
+        retry_in: Duration,
+        reason: String,
+    },
+    // No follows are requested, so no stream is running
+    Stopped,
+}
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
 }
 
 // Instead of deriving a bunch of data types that won't serve a purpose except to serialize JSON,
@@ -93,3 +241,164 @@ impl Serialize for SerializeWrapper<&entities::UrlEntity> {
         map.end()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        UpstreamStatus::Connecting,
+        r#"{"type":"upstream_status","data":{"state":"connecting"}}"#
+    )]
+    #[case(
+        UpstreamStatus::Connected,
+        r#"{"type":"upstream_status","data":{"state":"connected"}}"#
+    )]
+    #[case(
+        UpstreamStatus::BackingOff { retry_in: Duration::from_millis(250), reason: "stream stalled".into() },
+        r#"{"type":"upstream_status","data":{"state":"backing_off","retry_in":0.25,"reason":"stream stalled"}}"#
+    )]
+    #[case(
+        UpstreamStatus::Stopped,
+        r#"{"type":"upstream_status","data":{"state":"stopped"}}"#
+    )]
+    fn test_upstream_status(#[case] status: UpstreamStatus, #[case] expected: &str) {
+        let json = serde_json::to_string(&ServerMessage::UpstreamStatus(&status)).unwrap();
+        assert_eq!(json, expected);
+    }
+
+    #[rstest]
+    #[case(r#"{"type":"exit"}"#, None, "exit")]
+    #[case(r#"{"type":"exit","id":7}"#, Some(RequestId::Number(7)), "exit")]
+    #[case(
+        r#"{"id":"a","type":"set_subscriptions","data":[1]}"#,
+        Some(RequestId::String("a".into())),
+        "set_subscriptions"
+    )]
+    fn test_parse(#[case] data: &str, #[case] id: Option<RequestId>, #[case] kind: &str) {
+        let request = ClientRequest::parse(Encoding::Json, data.as_bytes()).unwrap();
+        assert_eq!(request.id, id);
+
+        let parsed_kind = match request.message {
+            ClientMessage::Exit => "exit",
+            ClientMessage::SetSubscriptions(_) => "set_subscriptions",
+            _ => "other",
+        };
+        assert_eq!(parsed_kind, kind);
+    }
+
+    #[rstest]
+    #[case("{", None, ErrorCode::InvalidJson)]
+    #[case(
+        r#"{"type":"subscribe","id":1}"#,
+        Some(RequestId::Number(1)),
+        ErrorCode::UnknownType
+    )]
+    #[case(
+        r#"{"type":"subscribe","data":[1],"id":1}"#,
+        Some(RequestId::Number(1)),
+        ErrorCode::UnknownType
+    )]
+    #[case(r#"{"data":[1]}"#, None, ErrorCode::InvalidMessage)]
+    #[case(
+        r#"{"type":"set_subscriptions","data":"a","id":"b"}"#,
+        Some(RequestId::String("b".into())),
+        ErrorCode::InvalidMessage
+    )]
+    fn test_parse_invalid(
+        #[case] data: &str,
+        #[case] id: Option<RequestId>,
+        #[case] code: ErrorCode,
+    ) {
+        let error = ClientRequest::parse(Encoding::Json, data.as_bytes()).unwrap_err();
+        assert_eq!(error.id, id);
+        assert_eq!(error.code, code);
+    }
+
+    #[rstest]
+    #[case(r#"{"type":"exit","id":1}"#, Some(RequestId::Number(1)))]
+    #[case(r#"{"type":"nope","data":{},"id":"a"}"#, Some(RequestId::String("a".into())))]
+    #[case(r#"{"type":"exit","id":[]}"#, None)]
+    #[case("{", None)]
+    fn test_peek_id(#[case] data: &str, #[case] expected: Option<RequestId>) {
+        assert_eq!(
+            ClientRequest::peek_id(Encoding::Json, data.as_bytes()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_reply() {
+        let follows = Follows::from([1]);
+        let id = RequestId::Number(3);
+
+        let json = serde_json::to_string(&Reply {
+            message: ServerMessage::AckSubscriptions(&follows),
+            id: Some(&id),
+            refused: None,
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"type":"ack_subscriptions","data":[1],"id":3}"#);
+
+        let refused = Follows::from([2]);
+        let json = serde_json::to_string(&Reply {
+            message: ServerMessage::AckSubscriptions(&follows),
+            id: None,
+            refused: Some(&refused),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"ack_subscriptions","data":[1],"refused":[2]}"#
+        );
+
+        let json = serde_json::to_string(&Reply {
+            message: ServerMessage::ProtocolError(ProtocolError {
+                code: ErrorCode::Unauthorized,
+                message: "nope",
+            }),
+            id: None,
+            refused: None,
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"protocol_error","data":{"code":"unauthorized","message":"nope"}}"#
+        );
+    }
+
+    #[rstest]
+    #[case(ErrorCode::InvalidJson, r#""invalid_json""#)]
+    #[case(ErrorCode::InvalidEncoding, r#""invalid_encoding""#)]
+    #[case(ErrorCode::UnknownType, r#""unknown_type""#)]
+    #[case(ErrorCode::InvalidMessage, r#""invalid_message""#)]
+    #[case(ErrorCode::Unauthorized, r#""unauthorized""#)]
+    #[case(ErrorCode::RateLimited, r#""rate_limited""#)]
+    #[case(ErrorCode::TooManyFollows, r#""too_many_follows""#)]
+    fn test_error_code(#[case] code: ErrorCode, #[case] expected: &str) {
+        assert_eq!(serde_json::to_string(&code).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_dropped() {
+        let json = serde_json::to_string(&ServerMessage::Dropped { count: 3 }).unwrap();
+        assert_eq!(json, r#"{"type":"dropped","data":{"count":3}}"#);
+    }
+
+    #[test]
+    fn test_session() {
+        let session = Session {
+            token: "0123456789abcdef",
+            resumed: true,
+            grace_period: Duration::from_secs(30),
+        };
+
+        let json = serde_json::to_string(&ServerMessage::Session(session)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"session","data":{"token":"0123456789abcdef","resumed":true,"grace_period":30.0}}"#
+        );
+    }
+}
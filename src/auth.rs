@@ -0,0 +1,176 @@
+use crate::{config, net::query_param};
+use anyhow::{Context, Result};
+use async_tungstenite::tungstenite::http::Request;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, ops::Not};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Subscriber,
+    // Can also use admin messages, such as `exit`
+    Admin,
+}
+
+// Who websocket clients are and what they're allowed to do.
+// Authentication is disabled when there are no tokens.
+#[derive(Debug, Default)]
+pub struct Auth {
+    tokens: HashMap<String, Role>,
+    exit_disabled: bool,
+}
+
+impl Auth {
+    pub async fn load(config: &config::WebSocket) -> Result<Self> {
+        let mut tokens = HashMap::new();
+
+        for (role, inline, path) in [
+            (Role::Subscriber, &config.tokens, &config.tokens_file),
+            (Role::Admin, &config.admin_tokens, &config.admin_tokens_file),
+        ] {
+            // admin tokens come last and win over subscriber tokens
+            tokens.extend(inline.iter().map(|token| (token.clone(), role)));
+
+            if let Some(path) = path {
+                let file = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("reading websocket tokens from {}", path.display()))?;
+
+                tokens.extend(parse_tokens_file(&file).map(|token| (token, role)));
+            }
+        }
+
+        Ok(Self {
+            tokens,
+            exit_disabled: config.disable_exit,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tokens.is_empty().not()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn role(&self, given: &str) -> Option<Role> {
+        // go through all of them so the time taken doesn't tell which one was closest
+        self.tokens.iter().fold(None, |found, (token, role)| {
+            if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+                Some(*role)
+            } else {
+                found
+            }
+        })
+    }
+
+    // Without authentication everyone is an admin, as it was before roles existed
+    pub const fn anonymous_role() -> Role {
+        Role::Admin
+    }
+
+    pub fn authorize_exit(&self, role: Role) -> Result<(), &'static str> {
+        if self.exit_disabled {
+            Err("exit is disabled")
+        } else if role < Role::Admin {
+            Err("exit requires the admin role")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// One token per line, blank lines and lines starting with # are ignored
+fn parse_tokens_file(file: &str) -> impl Iterator<Item = String> + '_ {
+    file.lines()
+        .map(str::trim)
+        .filter(|line| line.is_empty().not() && line.starts_with('#').not())
+        .map(String::from)
+}
+
+// Reads the token from either an `Authorization: Bearer` header or a `token` query parameter
+pub fn token_from_request<B>(request: &Request<B>) -> Option<String> {
+    let header = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    header
+        .map(String::from)
+        .or_else(|| query_param(request, "token"))
+}
+
+// Compares every byte so the time taken doesn't reveal how much of the token was right.
+// Both sides are hashed first, so that it doesn't reveal the length of the token either.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(&b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("Bearer hunter2", "/", Some("hunter2"))]
+    #[case("", "/?token=hunter2", Some("hunter2"))]
+    #[case("", "/?foo=bar&token=hunter2", Some("hunter2"))]
+    #[case("Bearer hunter2", "/?token=hunter3", Some("hunter2"))]
+    #[case("Basic aHVudGVyMg==", "/", None)]
+    #[case("", "/?token=hunter%262", Some("hunter&2"))]
+    #[case("", "/?token=hunter+2%2B", Some("hunter+2+"))]
+    #[case("", "/?token=hunter%FF", None)]
+    #[case("", "/?tokens=hunter2", None)]
+    #[case("", "/", None)]
+    fn test_token_from_request(
+        #[case] authorization: &str,
+        #[case] uri: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        let mut request = Request::builder().uri(uri);
+        if authorization.is_empty().not() {
+            request = request.header("Authorization", authorization);
+        }
+
+        let token = token_from_request(&request.body(()).unwrap());
+        assert_eq!(token.as_deref(), expected);
+    }
+
+    #[rstest]
+    #[case(false, Role::Admin, Ok(()))]
+    #[case(false, Role::Subscriber, Err("exit requires the admin role"))]
+    #[case(true, Role::Admin, Err("exit is disabled"))]
+    #[case(true, Role::Subscriber, Err("exit is disabled"))]
+    fn test_authorize_exit(
+        #[case] exit_disabled: bool,
+        #[case] role: Role,
+        #[case] expected: Result<(), &str>,
+    ) {
+        let auth = Auth {
+            tokens: HashMap::new(),
+            exit_disabled,
+        };
+
+        assert_eq!(auth.authorize_exit(role), expected);
+    }
+
+    #[rstest]
+    #[case("hunter2", "hunter2", true)]
+    #[case("hunter2", "hunter3", false)]
+    #[case("hunter2", "hunter", false)]
+    #[case("hunter2", "hunter22", false)]
+    #[case("hunter2", "", false)]
+    fn test_constant_time_eq(#[case] a: &str, #[case] b: &str, #[case] expected: bool) {
+        assert_eq!(constant_time_eq(a.as_bytes(), b.as_bytes()), expected);
+    }
+
+    #[test]
+    fn test_parse_tokens_file() {
+        let file = "# bots\nhunter2\n\n  hunter3  \n#hunter4\n";
+
+        let tokens: Vec<_> = parse_tokens_file(file).collect();
+        assert_eq!(tokens, ["hunter2", "hunter3"]);
+    }
+}
@@ -1,9 +1,15 @@
+// doc comments are rendered verbatim in --help
+#![allow(clippy::doc_markdown)]
+
 use clap::{Parser, ValueEnum};
 use egg_mode::{self as twitter};
 use serde::{Deserialize, Serialize};
 use std::{
-    net::SocketAddr,
+    fmt,
+    net::{AddrParseError, SocketAddr},
+    num::NonZeroU64,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -13,6 +19,73 @@ pub enum LogTimestamps {
     Off,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// What to do when a websocket client's outbound queue is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum SlowClientPolicy {
+    // Drop the oldest queued tweets
+    DropOldest,
+    // Close the connection
+    Disconnect,
+    // Drop the oldest queued tweets and tell the client how many it missed
+    Notify,
+}
+
+// Either address:port or unix:/path/to/socket
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix("unix:").map_or_else(
+            || s.parse().map(Self::Tcp),
+            |path| Ok(Self::Unix(path.into())),
+        )
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = AddrParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+// Octal file permissions, with or without a leading 0 or 0o
+fn parse_mode(s: &str) -> Result<u32, std::num::ParseIntError> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    u32::from_str_radix(digits, 8)
+}
+
 // This file is mostly boilerplate code
 
 // StructOpt derives an argument parser and environment reader
@@ -45,6 +118,15 @@ pub struct Args {
         env = "PAJBOT_LOG_TIMESTAMPS"
     )]
     pub log_timestamps: LogTimestamps,
+
+    /// Log output format, json writes one object per line with context fields
+    #[clap(
+        long = "log-format",
+        value_enum,
+        default_value = "text",
+        env = "PAJBOT_LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Parser)]
@@ -56,11 +138,30 @@ pub struct Config {
     #[serde(default)]
     #[clap(flatten)]
     pub twitter: Twitter,
+
+    #[serde(default)]
+    #[clap(flatten)]
+    pub http: Http,
+
+    // Lists of tables don't fit in arguments or environment variables, only read from the file
+    #[serde(default)]
+    #[clap(skip)]
+    pub webhooks: Vec<Webhook>,
+
+    #[serde(default)]
+    #[clap(skip)]
+    pub subscriptions: Vec<Subscription>,
+
+    #[serde(default)]
+    #[clap(skip)]
+    pub sinks: Sinks,
 }
 
+// struct_excessive_bools: each is a setting of its own, as given on the command line
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Clone, Debug, Deserialize, Serialize, Parser)]
 pub struct WebSocket {
-    /// address:port to bind the websocket listener to
+    /// address:port or unix:/path/to/socket to bind the websocket listener to
     #[serde(default = "WebSocket::default_listen_addr")]
     #[clap(
         short = 'l',
@@ -68,7 +169,196 @@ pub struct WebSocket {
         env = "PAJBOT_LISTEN",
         default_value = "127.0.0.1:2356"
     )]
-    pub listen_addr: SocketAddr,
+    pub listen_addr: ListenAddr,
+
+    /// Permissions of the unix socket in octal, such as 660
+    #[clap(long = "unix-socket-mode", env = "PAJBOT_UNIX_SOCKET_MODE", value_parser = parse_mode)]
+    pub unix_socket_mode: Option<u32>,
+
+    /// Tokens websocket clients must authenticate with, authentication is disabled if none are configured
+    #[serde(default)]
+    #[clap(
+        long = "websocket-token",
+        env = "PAJBOT_WEBSOCKET_TOKENS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub tokens: Vec<String>,
+
+    /// File to read more websocket tokens from, one per line
+    #[clap(long = "websocket-tokens-file", env = "PAJBOT_WEBSOCKET_TOKENS_FILE")]
+    pub tokens_file: Option<PathBuf>,
+
+    /// Tokens that also grant the admin role, required by admin messages such as `exit`
+    #[serde(default)]
+    #[clap(
+        long = "websocket-admin-token",
+        env = "PAJBOT_WEBSOCKET_ADMIN_TOKENS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub admin_tokens: Vec<String>,
+
+    /// File to read more websocket admin tokens from, one per line
+    #[clap(
+        long = "websocket-admin-tokens-file",
+        env = "PAJBOT_WEBSOCKET_ADMIN_TOKENS_FILE"
+    )]
+    pub admin_tokens_file: Option<PathBuf>,
+
+    /// Refuse the `exit` message from every client, admin or not
+    #[serde(default)]
+    #[clap(
+        long = "websocket-disable-exit",
+        env = "PAJBOT_WEBSOCKET_DISABLE_EXIT",
+        hide_env_values = true
+    )]
+    pub disable_exit: bool,
+
+    /// PEM certificate chain to serve wss:// with, requires the key as well
+    #[clap(long = "websocket-tls-cert", env = "PAJBOT_WEBSOCKET_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key to serve wss:// with, requires the certificate as well
+    #[clap(long = "websocket-tls-key", env = "PAJBOT_WEBSOCKET_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Compress messages with the permessage-deflate extension for websocket clients that offer it
+    #[serde(default)]
+    #[clap(
+        long = "websocket-deflate",
+        env = "PAJBOT_WEBSOCKET_DEFLATE",
+        hide_env_values = true
+    )]
+    pub deflate: bool,
+
+    /// Compression level of permessage-deflate, from 0 (none) to 9 (smallest)
+    #[serde(default = "WebSocket::default_deflate_level")]
+    #[clap(
+        long = "websocket-deflate-level",
+        env = "PAJBOT_WEBSOCKET_DEFLATE_LEVEL",
+        default_value = "6",
+        value_parser = clap::value_parser!(u32).range(0..=9)
+    )]
+    pub deflate_level: u32,
+
+    /// Compress every message sent on its own rather than with the previous ones, which takes less memory per client but compresses less
+    #[serde(default)]
+    #[clap(
+        long = "websocket-deflate-server-no-context-takeover",
+        env = "PAJBOT_WEBSOCKET_DEFLATE_SERVER_NO_CONTEXT_TAKEOVER",
+        hide_env_values = true
+    )]
+    pub deflate_server_no_context_takeover: bool,
+
+    /// Have websocket clients compress every message they send on its own
+    #[serde(default)]
+    #[clap(
+        long = "websocket-deflate-client-no-context-takeover",
+        env = "PAJBOT_WEBSOCKET_DEFLATE_CLIENT_NO_CONTEXT_TAKEOVER",
+        hide_env_values = true
+    )]
+    pub deflate_client_no_context_takeover: bool,
+
+    /// Seconds a disconnected client's follows are kept, and its tweets buffered, for it to resume its session. 0 disables sessions
+    #[serde(default)]
+    #[clap(
+        long = "websocket-session-grace-period",
+        env = "PAJBOT_WEBSOCKET_SESSION_GRACE_PERIOD",
+        default_value = "0"
+    )]
+    pub session_grace_period: u64,
+
+    /// Tweets queued for each websocket client before the slow client policy applies
+    #[serde(default = "WebSocket::default_queue_capacity")]
+    #[clap(
+        long = "websocket-queue-capacity",
+        env = "PAJBOT_WEBSOCKET_QUEUE_CAPACITY",
+        default_value = "64"
+    )]
+    pub queue_capacity: usize,
+
+    /// What to do when a websocket client's queue is full
+    #[serde(default = "WebSocket::default_slow_client_policy")]
+    #[clap(
+        long = "websocket-slow-client-policy",
+        env = "PAJBOT_WEBSOCKET_SLOW_CLIENT_POLICY",
+        value_enum,
+        default_value = "drop_oldest"
+    )]
+    pub slow_client_policy: SlowClientPolicy,
+
+    /// Seconds between pings sent to websocket clients
+    #[serde(default = "WebSocket::default_heartbeat_interval")]
+    #[clap(
+        long = "websocket-heartbeat-interval",
+        env = "PAJBOT_WEBSOCKET_HEARTBEAT_INTERVAL",
+        default_value = "30"
+    )]
+    pub heartbeat_interval: u64,
+
+    /// Seconds a websocket client has to answer a ping before it is disconnected
+    #[serde(default = "WebSocket::default_pong_timeout")]
+    #[clap(
+        long = "websocket-pong-timeout",
+        env = "PAJBOT_WEBSOCKET_PONG_TIMEOUT",
+        default_value = "60"
+    )]
+    pub pong_timeout: u64,
+
+    /// Seconds without any message from a websocket client before it is disconnected
+    #[serde(default = "WebSocket::default_idle_timeout")]
+    #[clap(
+        long = "websocket-idle-timeout",
+        env = "PAJBOT_WEBSOCKET_IDLE_TIMEOUT",
+        default_value = "90"
+    )]
+    pub idle_timeout: u64,
+
+    /// Most users a single websocket client may follow, further follows are refused
+    #[serde(default = "WebSocket::default_max_follows_per_client")]
+    #[clap(
+        long = "websocket-max-follows-per-client",
+        env = "PAJBOT_WEBSOCKET_MAX_FOLLOWS_PER_CLIENT",
+        default_value = "5000"
+    )]
+    pub max_follows_per_client: usize,
+
+    /// Most users followed by all websocket clients together, further follows are refused
+    #[serde(default = "WebSocket::default_max_follows")]
+    #[clap(
+        long = "websocket-max-follows",
+        env = "PAJBOT_WEBSOCKET_MAX_FOLLOWS",
+        default_value = "5000"
+    )]
+    pub max_follows: usize,
+
+    /// Messages a websocket client may send per second on average, 0 disables the limit
+    #[serde(default = "WebSocket::default_rate_limit_per_second")]
+    #[clap(
+        long = "websocket-rate-limit-per-second",
+        env = "PAJBOT_WEBSOCKET_RATE_LIMIT_PER_SECOND",
+        default_value = "5"
+    )]
+    pub rate_limit_per_second: u32,
+
+    /// Messages a websocket client may send at once before the rate limit applies
+    #[serde(default = "WebSocket::default_rate_limit_burst")]
+    #[clap(
+        long = "websocket-rate-limit-burst",
+        env = "PAJBOT_WEBSOCKET_RATE_LIMIT_BURST",
+        default_value = "20"
+    )]
+    pub rate_limit_burst: u32,
+
+    /// Messages over the rate limit a websocket client may send before it is disconnected
+    #[serde(default = "WebSocket::default_rate_limit_max_violations")]
+    #[clap(
+        long = "websocket-rate-limit-max-violations",
+        env = "PAJBOT_WEBSOCKET_RATE_LIMIT_MAX_VIOLATIONS",
+        default_value = "10"
+    )]
+    pub rate_limit_max_violations: u32,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, Parser)]
@@ -116,11 +406,180 @@ pub struct Twitter {
     pub always_restart: bool,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, Parser)]
+pub struct Http {
+    /// address:port to bind the HTTP status listener to, disabled if unset
+    #[clap(
+        id = "http_listen_addr",
+        value_name = "LISTEN_ADDR",
+        long = "http-listen",
+        env = "PAJBOT_HTTP_LISTEN"
+    )]
+    pub listen_addr: Option<SocketAddr>,
+
+    /// Bearer token required by the admin endpoints, which are disabled if unset
+    #[clap(
+        long = "http-admin-token",
+        env = "PAJBOT_HTTP_ADMIN_TOKEN",
+        hide_env_values = true
+    )]
+    pub admin_token: Option<String>,
+
+    /// Seconds the twitter stream may be down for, while follows are requested, before /readyz fails
+    #[serde(default = "Http::default_readiness_max_backoff")]
+    #[clap(
+        long = "http-readiness-max-backoff",
+        env = "PAJBOT_HTTP_READINESS_MAX_BACKOFF",
+        default_value = "60"
+    )]
+    pub readiness_max_backoff: u64,
+
+    /// Seconds between the keep-alive comments sent to idle /events streams
+    #[serde(default = "Http::default_sse_keep_alive")]
+    #[clap(
+        long = "http-sse-keep-alive",
+        env = "PAJBOT_HTTP_SSE_KEEP_ALIVE",
+        default_value = "30"
+    )]
+    pub sse_keep_alive: u64,
+}
+
+// A URL the tweets of some users are POSTed to, as `[[webhooks]]` tables
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Webhook {
+    pub url: String,
+
+    // Twitter user ids whose tweets are posted
+    #[serde(default)]
+    pub follows: Vec<u64>,
+
+    // Key each request body is signed with, unsigned if unset
+    pub secret: Option<String>,
+
+    // Times a failed delivery is retried before it is given up on
+    #[serde(default = "Webhook::default_max_retries")]
+    pub max_retries: u32,
+
+    // File deliveries that were given up on are appended to, one JSON object per line
+    pub dead_letter_file: Option<PathBuf>,
+}
+
+// Users that are always followed, as `[[subscriptions]]` tables
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Subscription {
+    // Twitter user ids
+    #[serde(default)]
+    pub follows: Vec<u64>,
+
+    // Screen names, with or without the @, looked up once the service starts
+    #[serde(default)]
+    pub handles: Vec<String>,
+}
+
+// Outputs tweets are published to besides websocket clients, each built with a feature
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Sinks {
+    pub redis: Option<RedisSink>,
+    pub nats: Option<NatsSink>,
+    pub mqtt: Option<MqttSink>,
+}
+
+// Publishes every tweet to redis channels, with the `redis` feature.
+// Channels are templates, `{user_id}`, `{screen_name}` and `{tweet_id}` are replaced by those
+// of the tweet, an empty channel is not published to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RedisSink {
+    #[serde(default = "RedisSink::default_url")]
+    pub url: String,
+
+    #[serde(default = "RedisSink::default_channel")]
+    pub channel: String,
+
+    // Channel every tweet is also published to
+    #[serde(default = "RedisSink::default_firehose_channel")]
+    pub firehose_channel: String,
+
+    // Seconds before reconnecting once the connection is lost, doubled every failed attempt
+    #[serde(default = "default_reconnect_initial_backoff")]
+    pub reconnect_initial_backoff: NonZeroU64,
+
+    #[serde(default = "default_reconnect_max_backoff")]
+    pub reconnect_max_backoff: NonZeroU64,
+}
+
+// Publishes every tweet to NATS subjects, with the `nats` feature.
+// Subjects are templates like redis channels.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NatsSink {
+    #[serde(default = "NatsSink::default_url")]
+    pub url: String,
+
+    #[serde(default = "NatsSink::default_subject")]
+    pub subject: String,
+
+    // Subscribers can use wildcards instead, so there is none by default
+    #[serde(default)]
+    pub firehose_subject: String,
+
+    #[serde(default = "default_reconnect_initial_backoff")]
+    pub reconnect_initial_backoff: NonZeroU64,
+
+    #[serde(default = "default_reconnect_max_backoff")]
+    pub reconnect_max_backoff: NonZeroU64,
+}
+
+// Publishes every tweet to MQTT topics, with the `mqtt` feature.
+// Topics are templates like redis channels.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MqttSink {
+    #[serde(default = "MqttSink::default_host")]
+    pub host: String,
+
+    #[serde(default = "MqttSink::default_port")]
+    pub port: u16,
+
+    #[serde(default = "MqttSink::default_client_id")]
+    pub client_id: String,
+
+    pub username: Option<String>,
+
+    pub password: Option<String>,
+
+    #[serde(default = "MqttSink::default_topic")]
+    pub topic: String,
+
+    // Subscribers can use wildcards instead, so there is none by default
+    #[serde(default)]
+    pub firehose_topic: String,
+
+    // 0, 1 or 2
+    #[serde(default = "MqttSink::default_qos")]
+    pub qos: u8,
+
+    #[serde(default = "default_reconnect_initial_backoff")]
+    pub reconnect_initial_backoff: NonZeroU64,
+
+    #[serde(default = "default_reconnect_max_backoff")]
+    pub reconnect_max_backoff: NonZeroU64,
+}
+
 impl Config {
     pub fn merge(self, other: Self) -> Self {
         Self {
             websocket: self.websocket.merge(&other.websocket),
             twitter: self.twitter.merge(other.twitter),
+            http: self.http.merge(other.http),
+            webhooks: if self.webhooks.is_empty() {
+                other.webhooks
+            } else {
+                self.webhooks
+            },
+            subscriptions: if self.subscriptions.is_empty() {
+                other.subscriptions
+            } else {
+                self.subscriptions
+            },
+            sinks: other.sinks,
         }
     }
 
@@ -133,17 +592,151 @@ impl Config {
 }
 
 impl WebSocket {
-    pub fn default_listen_addr() -> SocketAddr {
-        "127.0.0.1:2356".parse().unwrap()
+    pub fn default_listen_addr() -> ListenAddr {
+        ListenAddr::Tcp("127.0.0.1:2356".parse().unwrap())
+    }
+
+    pub const fn default_deflate_level() -> u32 {
+        6
+    }
+
+    pub const fn default_queue_capacity() -> usize {
+        64
+    }
+
+    pub const fn default_slow_client_policy() -> SlowClientPolicy {
+        SlowClientPolicy::DropOldest
+    }
+
+    pub const fn default_heartbeat_interval() -> u64 {
+        30
+    }
+
+    pub const fn default_pong_timeout() -> u64 {
+        60
+    }
+
+    pub const fn default_idle_timeout() -> u64 {
+        90
+    }
+
+    // the twitter filter stream doesn't accept more than this
+    pub const fn default_max_follows_per_client() -> usize {
+        5000
+    }
+
+    pub const fn default_max_follows() -> usize {
+        5000
+    }
+
+    pub const fn default_rate_limit_per_second() -> u32 {
+        5
+    }
+
+    pub const fn default_rate_limit_burst() -> u32 {
+        20
+    }
+
+    pub const fn default_rate_limit_max_violations() -> u32 {
+        10
     }
 
     pub fn merge(self, other: &Self) -> Self {
         Self {
             listen_addr: if self.listen_addr == Self::default_listen_addr() {
-                other.listen_addr
+                other.listen_addr.clone()
             } else {
                 self.listen_addr
             },
+            unix_socket_mode: self.unix_socket_mode.or(other.unix_socket_mode),
+            tokens: if self.tokens.is_empty() {
+                other.tokens.clone()
+            } else {
+                self.tokens
+            },
+            tokens_file: self.tokens_file.or_else(|| other.tokens_file.clone()),
+            admin_tokens: if self.admin_tokens.is_empty() {
+                other.admin_tokens.clone()
+            } else {
+                self.admin_tokens
+            },
+            admin_tokens_file: self
+                .admin_tokens_file
+                .or_else(|| other.admin_tokens_file.clone()),
+            disable_exit: self.disable_exit || other.disable_exit,
+            tls_cert: self.tls_cert.or_else(|| other.tls_cert.clone()),
+            tls_key: self.tls_key.or_else(|| other.tls_key.clone()),
+            deflate: self.deflate || other.deflate,
+            deflate_level: if self.deflate_level == Self::default_deflate_level() {
+                other.deflate_level
+            } else {
+                self.deflate_level
+            },
+            deflate_server_no_context_takeover: self.deflate_server_no_context_takeover
+                || other.deflate_server_no_context_takeover,
+            deflate_client_no_context_takeover: self.deflate_client_no_context_takeover
+                || other.deflate_client_no_context_takeover,
+            session_grace_period: if self.session_grace_period == 0 {
+                other.session_grace_period
+            } else {
+                self.session_grace_period
+            },
+            queue_capacity: if self.queue_capacity == Self::default_queue_capacity() {
+                other.queue_capacity
+            } else {
+                self.queue_capacity
+            },
+            slow_client_policy: if self.slow_client_policy == Self::default_slow_client_policy() {
+                other.slow_client_policy
+            } else {
+                self.slow_client_policy
+            },
+            heartbeat_interval: if self.heartbeat_interval == Self::default_heartbeat_interval() {
+                other.heartbeat_interval
+            } else {
+                self.heartbeat_interval
+            },
+            pong_timeout: if self.pong_timeout == Self::default_pong_timeout() {
+                other.pong_timeout
+            } else {
+                self.pong_timeout
+            },
+            idle_timeout: if self.idle_timeout == Self::default_idle_timeout() {
+                other.idle_timeout
+            } else {
+                self.idle_timeout
+            },
+            max_follows_per_client: if self.max_follows_per_client
+                == Self::default_max_follows_per_client()
+            {
+                other.max_follows_per_client
+            } else {
+                self.max_follows_per_client
+            },
+            max_follows: if self.max_follows == Self::default_max_follows() {
+                other.max_follows
+            } else {
+                self.max_follows
+            },
+            rate_limit_per_second: if self.rate_limit_per_second
+                == Self::default_rate_limit_per_second()
+            {
+                other.rate_limit_per_second
+            } else {
+                self.rate_limit_per_second
+            },
+            rate_limit_burst: if self.rate_limit_burst == Self::default_rate_limit_burst() {
+                other.rate_limit_burst
+            } else {
+                self.rate_limit_burst
+            },
+            rate_limit_max_violations: if self.rate_limit_max_violations
+                == Self::default_rate_limit_max_violations()
+            {
+                other.rate_limit_max_violations
+            } else {
+                self.rate_limit_max_violations
+            },
         }
     }
 }
@@ -152,6 +745,29 @@ impl Default for WebSocket {
     fn default() -> Self {
         Self {
             listen_addr: Self::default_listen_addr(),
+            unix_socket_mode: None,
+            tokens: Vec::new(),
+            tokens_file: None,
+            admin_tokens: Vec::new(),
+            admin_tokens_file: None,
+            disable_exit: false,
+            tls_cert: None,
+            tls_key: None,
+            deflate: false,
+            deflate_level: Self::default_deflate_level(),
+            deflate_server_no_context_takeover: false,
+            deflate_client_no_context_takeover: false,
+            session_grace_period: 0,
+            queue_capacity: Self::default_queue_capacity(),
+            slow_client_policy: Self::default_slow_client_policy(),
+            heartbeat_interval: Self::default_heartbeat_interval(),
+            pong_timeout: Self::default_pong_timeout(),
+            idle_timeout: Self::default_idle_timeout(),
+            max_follows_per_client: Self::default_max_follows_per_client(),
+            max_follows: Self::default_max_follows(),
+            rate_limit_per_second: Self::default_rate_limit_per_second(),
+            rate_limit_burst: Self::default_rate_limit_burst(),
+            rate_limit_max_violations: Self::default_rate_limit_max_violations(),
         }
     }
 }
@@ -168,7 +784,7 @@ impl Twitter {
     }
 
     pub fn token(&self) -> twitter::Token {
-        let x = |s: &Option<String>| s.as_ref().cloned().unwrap();
+        let x = |s: &Option<String>| s.clone().unwrap();
 
         twitter::Token::Access {
             consumer: twitter::KeyPair::new(x(&self.consumer_key), x(&self.consumer_secret)),
@@ -176,3 +792,160 @@ impl Twitter {
         }
     }
 }
+
+impl Http {
+    pub const fn default_readiness_max_backoff() -> u64 {
+        60
+    }
+
+    pub const fn default_sse_keep_alive() -> u64 {
+        30
+    }
+
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            listen_addr: self.listen_addr.or(other.listen_addr),
+            admin_token: self.admin_token.or(other.admin_token),
+            readiness_max_backoff: if self.readiness_max_backoff
+                == Self::default_readiness_max_backoff()
+            {
+                other.readiness_max_backoff
+            } else {
+                self.readiness_max_backoff
+            },
+            sse_keep_alive: if self.sse_keep_alive == Self::default_sse_keep_alive() {
+                other.sse_keep_alive
+            } else {
+                self.sse_keep_alive
+            },
+        }
+    }
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            listen_addr: None,
+            admin_token: None,
+            readiness_max_backoff: Self::default_readiness_max_backoff(),
+            sse_keep_alive: Self::default_sse_keep_alive(),
+        }
+    }
+}
+
+impl Webhook {
+    pub const fn default_max_retries() -> u32 {
+        5
+    }
+}
+
+impl Sinks {
+    // Of the configured sinks, whether they were built or not
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            ("redis", self.redis.is_some()),
+            ("nats", self.nats.is_some()),
+            ("mqtt", self.mqtt.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, configured)| configured.then_some(name))
+        .collect()
+    }
+}
+
+impl RedisSink {
+    pub fn default_url() -> String {
+        "redis://127.0.0.1:6379/".into()
+    }
+
+    pub fn default_channel() -> String {
+        "tweets:{user_id}".into()
+    }
+
+    pub fn default_firehose_channel() -> String {
+        "tweets".into()
+    }
+}
+
+impl NatsSink {
+    pub fn default_url() -> String {
+        "nats://127.0.0.1:4222".into()
+    }
+
+    pub fn default_subject() -> String {
+        "tweets.{user_id}".into()
+    }
+}
+
+impl MqttSink {
+    pub fn default_host() -> String {
+        "127.0.0.1".into()
+    }
+
+    pub const fn default_port() -> u16 {
+        1883
+    }
+
+    pub fn default_client_id() -> String {
+        "tweet-provider".into()
+    }
+
+    pub fn default_topic() -> String {
+        "tweets/{user_id}".into()
+    }
+
+    pub const fn default_qos() -> u8 {
+        1
+    }
+}
+
+// Not zero, or a lost connection would be retried in a hot loop
+pub const fn default_reconnect_initial_backoff() -> NonZeroU64 {
+    NonZeroU64::MIN
+}
+
+pub const fn default_reconnect_max_backoff() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("127.0.0.1:2356", Some(ListenAddr::Tcp("127.0.0.1:2356".parse().unwrap())))]
+    #[case("[::1]:2356", Some(ListenAddr::Tcp("[::1]:2356".parse().unwrap())))]
+    #[case(
+        "unix:/run/tweet-provider.sock",
+        Some(ListenAddr::Unix("/run/tweet-provider.sock".into()))
+    )]
+    #[case("/run/tweet-provider.sock", None)]
+    fn test_listen_addr(#[case] input: &str, #[case] expected: Option<ListenAddr>) {
+        let addr = input.parse::<ListenAddr>().ok();
+        assert_eq!(addr, expected);
+
+        if let Some(addr) = addr {
+            assert_eq!(addr.to_string(), input);
+        }
+    }
+
+    #[rstest]
+    #[case("660", Some(0o660))]
+    #[case("0660", Some(0o660))]
+    #[case("0o600", Some(0o600))]
+    #[case("698", None)]
+    fn test_parse_mode(#[case] input: &str, #[case] expected: Option<u32>) {
+        assert_eq!(parse_mode(input).ok(), expected);
+    }
+
+    #[rstest]
+    #[case("[redis]\nreconnect_initial_backoff = 2", true)]
+    #[case("[redis]\nreconnect_initial_backoff = 0", false)]
+    #[case("[redis]\nreconnect_max_backoff = 0", false)]
+    #[case("[nats]\nreconnect_initial_backoff = 0", false)]
+    #[case("[mqtt]\nreconnect_initial_backoff = 0", false)]
+    fn test_reconnect_backoff(#[case] input: &str, #[case] valid: bool) {
+        assert_eq!(toml::from_str::<Sinks>(input).is_ok(), valid);
+    }
+}
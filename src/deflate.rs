@@ -0,0 +1,744 @@
+use crate::config;
+use async_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    http::HeaderValue,
+    protocol::frame::{
+        coding::{Data, OpCode},
+        FrameHeader,
+    },
+};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{
+    io::{self, Cursor},
+    ops::Not,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Same as tungstenite's default, which only checks messages once they're inflated
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+// Ends every compressed message, and is left out of the frames
+const SYNC_FLUSH_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// The permessage-deflate extension (RFC 7692), negotiated with the `Sec-WebSocket-Extensions`
+// header during the handshake.
+// tungstenite knows nothing of extensions, so `DeflateStream` compresses and inflates frames
+// on their way between it and the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deflate {
+    level: u32,
+    // the server compresses every message on its own
+    server_no_context_takeover: bool,
+    // the client compresses every message on its own
+    client_no_context_takeover: bool,
+}
+
+impl Deflate {
+    // Accepts the first of the permessage-deflate offers made by the client that is supported,
+    // None when it made none or the extension is disabled
+    pub fn negotiate(
+        config: &config::WebSocket,
+        request: &Request,
+        response: &mut Response,
+    ) -> Option<Self> {
+        if config.deflate.not() {
+            return None;
+        }
+
+        let offer = request
+            .headers()
+            .get_all("sec-websocket-extensions")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(Offer::parse)?;
+
+        let deflate = Self {
+            level: config.deflate_level,
+            server_no_context_takeover: config.deflate_server_no_context_takeover
+                || offer.server_no_context_takeover,
+            client_no_context_takeover: config.deflate_client_no_context_takeover,
+        };
+
+        let mut accepted = "permessage-deflate".to_owned();
+        if deflate.server_no_context_takeover {
+            accepted.push_str("; server_no_context_takeover");
+        }
+        if deflate.client_no_context_takeover {
+            accepted.push_str("; client_no_context_takeover");
+        }
+        response.headers_mut().insert(
+            "sec-websocket-extensions",
+            HeaderValue::from_str(&accepted).ok()?,
+        );
+        Some(deflate)
+    }
+}
+
+// A permessage-deflate offer from a client, with the parameters that matter to the server
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Offer {
+    server_no_context_takeover: bool,
+}
+
+impl Offer {
+    // None when the extension isn't permessage-deflate, or the offer can't be accepted
+    fn parse(extension: &str) -> Option<Self> {
+        let mut params = extension.split(';').map(str::trim);
+        if params.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let mut offer = Self::default();
+        let mut seen = Vec::new();
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            // parameters may only be given once
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+
+            let window_bits = value.map(str::parse::<u8>);
+            match (name, window_bits) {
+                ("server_no_context_takeover", None) => offer.server_no_context_takeover = true,
+                // the compressor always uses the largest window, it can't be asked for less,
+                // and inflating with the largest window works with whatever the client uses
+                ("client_no_context_takeover", None)
+                | ("server_max_window_bits", Some(Ok(15)))
+                | ("client_max_window_bits", None | Some(Ok(8..=15))) => {}
+                _ => return None,
+            }
+        }
+        Some(offer)
+    }
+}
+
+// Sits between tungstenite and the connection, passing bytes through until `enable` is
+// called once the handshake is done.
+// Then compresses the text and binary messages tungstenite sends, and inflates the compressed
+// messages received before tungstenite reads them.
+pub struct DeflateStream<S> {
+    inner: S,
+    codec: Option<Codec>,
+    // read from the connection, up to the end of the last whole frame
+    read_raw: Vec<u8>,
+    // ready for tungstenite to read
+    read_out: Vec<u8>,
+    read_pos: usize,
+    // written by tungstenite, up to the end of the last whole frame
+    write_raw: Vec<u8>,
+    // ready to be written to the connection
+    write_out: Vec<u8>,
+    write_pos: usize,
+}
+
+struct Codec {
+    deflate: Deflate,
+    compress: Compress,
+    decompress: Decompress,
+    // opcode and payload of a compressed message received in fragments
+    fragmented: Option<(OpCode, Vec<u8>)>,
+}
+
+impl<S> DeflateStream<S> {
+    pub const fn new(inner: S) -> Self {
+        Self {
+            inner,
+            codec: None,
+            read_raw: Vec::new(),
+            read_out: Vec::new(),
+            read_pos: 0,
+            write_raw: Vec::new(),
+            write_out: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    pub fn enable(&mut self, deflate: Deflate) {
+        self.codec = Some(Codec {
+            deflate,
+            compress: Compress::new(Compression::new(deflate.level), false),
+            decompress: Decompress::new(false),
+            fragmented: None,
+        });
+    }
+}
+
+impl Codec {
+    // Inflates the compressed messages among the frames sent by the client
+    fn read_frames(&mut self, raw: &mut Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut consumed = 0;
+        while let Some((header, payload)) = next_frame(&raw[consumed..])? {
+            let frame_len = header.len(payload.len() as u64) + payload.len();
+            let compressed = match header.opcode {
+                OpCode::Data(Data::Text | Data::Binary) if self.fragmented.is_some() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "new message before the end of a fragmented one",
+                    ));
+                }
+                OpCode::Data(Data::Text | Data::Binary) => header.rsv1,
+                OpCode::Data(Data::Continue) => self.fragmented.is_some() && header.rsv1.not(),
+                // tungstenite rejects anything else there is to reject
+                _ => false,
+            };
+            if compressed.not() {
+                out.extend_from_slice(&raw[consumed..consumed + frame_len]);
+                consumed += frame_len;
+                continue;
+            }
+
+            let mut payload = payload.to_vec();
+            consumed += frame_len;
+            if let Some(mask) = header.mask {
+                apply_mask(&mut payload, mask);
+            }
+            let (opcode, message) = match self.fragmented.take() {
+                Some((opcode, mut message)) => {
+                    message.extend_from_slice(&payload);
+                    (opcode, message)
+                }
+                None => (header.opcode, payload),
+            };
+            if message.len() > MAX_MESSAGE_SIZE {
+                return Err(too_large());
+            }
+            if header.is_final.not() {
+                self.fragmented = Some((opcode, message));
+                continue;
+            }
+
+            let message = self.inflate(message)?;
+            let header = FrameHeader {
+                is_final: true,
+                opcode,
+                // tungstenite expects client frames to be masked, which a zero mask leaves as is
+                mask: Some([0; 4]),
+                ..FrameHeader::default()
+            };
+            header
+                .format(message.len() as u64, out)
+                .map_err(io::Error::other)?;
+            out.extend_from_slice(&message);
+        }
+        raw.drain(..consumed);
+        Ok(())
+    }
+
+    // Compresses the text and binary messages among the frames written by tungstenite
+    fn write_frames(&mut self, raw: &mut Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut consumed = 0;
+        while let Some((mut header, payload)) = next_frame(&raw[consumed..])? {
+            let frame_len = header.len(payload.len() as u64) + payload.len();
+
+            // tungstenite doesn't fragment the messages it sends, any that are go out as they are
+            if matches!(header.opcode, OpCode::Data(Data::Text | Data::Binary)) && header.is_final {
+                let payload = self.compress(payload)?;
+                header.rsv1 = true;
+                header
+                    .format(payload.len() as u64, out)
+                    .map_err(io::Error::other)?;
+                out.extend_from_slice(&payload);
+            } else {
+                out.extend_from_slice(&raw[consumed..consumed + frame_len]);
+            }
+            consumed += frame_len;
+        }
+        raw.drain(..consumed);
+        Ok(())
+    }
+
+    fn compress(&mut self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(message.len() / 2 + 64);
+        let total_in = self.compress.total_in();
+        loop {
+            let consumed = usize::try_from(self.compress.total_in() - total_in).unwrap();
+            self.compress
+                .compress_vec(&message[consumed..], &mut out, FlushCompress::Sync)?;
+            let consumed = usize::try_from(self.compress.total_in() - total_in).unwrap();
+            // a sync flush is complete once everything is consumed with room left in the output
+            if consumed == message.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+
+        if out.ends_with(&SYNC_FLUSH_TAIL) {
+            out.truncate(out.len() - SYNC_FLUSH_TAIL.len());
+        }
+        if self.deflate.server_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    fn inflate(&mut self, mut message: Vec<u8>) -> io::Result<Vec<u8>> {
+        message.extend_from_slice(&SYNC_FLUSH_TAIL);
+
+        let mut out = Vec::with_capacity(message.len() * 4);
+        let total_in = self.decompress.total_in();
+        loop {
+            let consumed = usize::try_from(self.decompress.total_in() - total_in).unwrap();
+            let written = out.len();
+            let status = self
+                .decompress
+                .decompress_vec(&message[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+            let progress = usize::try_from(self.decompress.total_in() - total_in).unwrap();
+
+            if out.len() > MAX_MESSAGE_SIZE {
+                return Err(too_large());
+            }
+            // a client may end its deflate stream with a message, the next one starts a new one
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                break;
+            }
+            if progress == message.len() && out.len() < out.capacity() {
+                break;
+            }
+            if progress == consumed && out.len() == written && out.len() < out.capacity() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated compressed message",
+                ));
+            }
+            out.reserve(out.capacity().max(64));
+        }
+
+        if self.deflate.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+// The header and payload of the frame at the start of `buf`, None until it's all there
+fn next_frame(buf: &[u8]) -> io::Result<Option<(FrameHeader, &[u8])>> {
+    let mut cursor = Cursor::new(buf);
+    let Some((header, len)) = FrameHeader::parse(&mut cursor)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?
+    else {
+        return Ok(None);
+    };
+
+    let start = usize::try_from(cursor.position()).unwrap();
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= MAX_MESSAGE_SIZE)
+        .ok_or_else(too_large)?;
+    Ok(buf.get(start..start + len).map(|payload| (header, payload)))
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "websocket message too large")
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(codec) = &mut this.codec else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if this.read_pos < this.read_out.len() {
+                let ready = &this.read_out[this.read_pos..];
+                let len = ready.len().min(buf.remaining());
+                buf.put_slice(&ready[..len]);
+                this.read_pos += len;
+                if this.read_pos == this.read_out.len() {
+                    this.read_out.clear();
+                    this.read_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0; 8 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // the connection is closed, tungstenite makes sense of where that leaves it
+                return Poll::Ready(Ok(()));
+            }
+            this.read_raw.extend_from_slice(chunk.filled());
+            codec.read_frames(&mut this.read_raw, &mut this.read_out)?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    // Writes out as much of the compressed frames as the connection takes
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_out.len() {
+            let written = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_out[self.write_pos..])
+            )?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += written;
+        }
+        self.write_out.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.codec.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // compressed frames wait for the connection to take the previous ones
+        ready!(this.poll_write_out(cx))?;
+        this.write_raw.extend_from_slice(buf);
+        if let Some(codec) = &mut this.codec {
+            codec.write_frames(&mut this.write_raw, &mut this.write_out)?;
+        }
+        if let Poll::Ready(Err(error)) = this.poll_write_out(cx) {
+            return Poll::Ready(Err(error));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_tungstenite as ws;
+    use async_tungstenite::tungstenite::protocol::frame::coding::Control;
+    use futures::{SinkExt, StreamExt};
+    use rstest::rstest;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const fn deflate(server_no_context_takeover: bool) -> Deflate {
+        Deflate {
+            level: 6,
+            server_no_context_takeover,
+            client_no_context_takeover: false,
+        }
+    }
+
+    // Compresses and inflates as a client would
+    fn client_codec() -> Codec {
+        Codec {
+            deflate: deflate(false),
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            fragmented: None,
+        }
+    }
+
+    fn frame(opcode: OpCode, is_final: bool, rsv1: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let header = FrameHeader {
+            is_final,
+            rsv1,
+            opcode,
+            mask: Some(mask),
+            ..FrameHeader::default()
+        };
+        let mut frame = Vec::new();
+        header.format(payload.len() as u64, &mut frame).unwrap();
+        let mut payload = payload.to_vec();
+        apply_mask(&mut payload, mask);
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    // Reads from `stream` until a whole frame is there
+    async fn read_frame(
+        stream: &mut (impl AsyncRead + Unpin),
+        buf: &mut Vec<u8>,
+    ) -> (FrameHeader, Vec<u8>) {
+        loop {
+            if let Some((header, payload)) = next_frame(buf).unwrap() {
+                let payload = payload.to_vec();
+                buf.drain(..header.len(payload.len() as u64) + payload.len());
+                return (header, payload);
+            }
+            let mut chunk = [0; 1024];
+            let len = stream.read(&mut chunk).await.unwrap();
+            assert_ne!(len, 0);
+            buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    #[rstest]
+    #[case(true, None, None, None)]
+    #[case(false, Some("permessage-deflate"), None, None)]
+    #[case(true, Some("x-webkit-deflate-frame"), None, None)]
+    #[case(
+        true,
+        Some("permessage-deflate; client_max_window_bits"),
+        Some(deflate(false)),
+        Some("permessage-deflate")
+    )]
+    #[case(
+        true,
+        Some("permessage-deflate; server_no_context_takeover"),
+        Some(deflate(true)),
+        Some("permessage-deflate; server_no_context_takeover")
+    )]
+    #[case(
+        true,
+        Some("permessage-deflate; server_max_window_bits=10, permessage-deflate"),
+        Some(deflate(false)),
+        Some("permessage-deflate")
+    )]
+    #[case(
+        true,
+        Some("permessage-deflate; server_max_window_bits=10"),
+        None,
+        None
+    )]
+    #[case(
+        true,
+        Some("permessage-deflate; client_no_context_takeover; client_no_context_takeover"),
+        None,
+        None
+    )]
+    #[case(true, Some("permessage-deflate; x-unknown"), None, None)]
+    fn test_negotiate(
+        #[case] enabled: bool,
+        #[case] offered: Option<&str>,
+        #[case] expected: Option<Deflate>,
+        #[case] expected_header: Option<&str>,
+    ) {
+        let config = config::WebSocket {
+            deflate: enabled,
+            ..config::WebSocket::default()
+        };
+        let mut request = Request::builder().uri("/");
+        if let Some(offered) = offered {
+            request = request.header("sec-websocket-extensions", offered);
+        }
+        let request = request.body(()).unwrap();
+        let mut response = Response::default();
+
+        assert_eq!(
+            Deflate::negotiate(&config, &request, &mut response),
+            expected
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get("sec-websocket-extensions")
+                .map(|value| value.to_str().unwrap()),
+            expected_header
+        );
+    }
+
+    #[test]
+    fn test_negotiate_context_takeover() {
+        let config = config::WebSocket {
+            deflate: true,
+            deflate_server_no_context_takeover: true,
+            deflate_client_no_context_takeover: true,
+            ..config::WebSocket::default()
+        };
+        let request = Request::builder()
+            .uri("/")
+            .header("sec-websocket-extensions", "permessage-deflate")
+            .body(())
+            .unwrap();
+        let mut response = Response::default();
+
+        let deflate = Deflate::negotiate(&config, &request, &mut response).unwrap();
+        assert!(deflate.server_no_context_takeover);
+        assert!(deflate.client_no_context_takeover);
+        assert_eq!(
+            response.headers()["sec-websocket-extensions"],
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn test_write(#[case] server_no_context_takeover: bool) {
+        let (mut client, server) = duplex(64 * 1024);
+        let mut server = DeflateStream::new(server);
+        server.enable(deflate(server_no_context_takeover));
+        let mut client_codec = client_codec();
+        let message = br#"{"type":"tweet","data":{"text":"hello hello hello"}}"#;
+
+        // tungstenite sends unmasked frames, which may be written in pieces
+        let mut unmasked = Vec::new();
+        let header = FrameHeader {
+            opcode: OpCode::Data(Data::Text),
+            ..FrameHeader::default()
+        };
+        header.format(message.len() as u64, &mut unmasked).unwrap();
+        unmasked.extend_from_slice(message);
+        let ping = [0x89, 0x00];
+
+        let mut buf = Vec::new();
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            server.write_all(&unmasked[..3]).await.unwrap();
+            server.write_all(&unmasked[3..]).await.unwrap();
+            server.write_all(&ping).await.unwrap();
+            server.flush().await.unwrap();
+
+            let (header, payload) = read_frame(&mut client, &mut buf).await;
+            assert!(header.rsv1);
+            assert_eq!(header.opcode, OpCode::Data(Data::Text));
+            sizes.push(payload.len());
+            assert_eq!(client_codec.inflate(payload).unwrap(), message);
+
+            // control frames are left as they are
+            let (header, payload) = read_frame(&mut client, &mut buf).await;
+            assert!(header.rsv1.not());
+            assert_eq!(header.opcode, OpCode::Control(Control::Ping));
+            assert!(payload.is_empty());
+        }
+
+        // the second message is compressed with the first one, unless that's been turned off
+        assert_eq!(sizes[0] == sizes[1], server_no_context_takeover);
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let (mut client, server) = duplex(64 * 1024);
+        let mut server = DeflateStream::new(server);
+        server.enable(deflate(false));
+        let mut client_codec = client_codec();
+        let message = br#"{"type":"set_subscriptions","data":[1,1,1,1,1,1]}"#;
+
+        // a compressed message in two fragments, with a ping between them, then an uncompressed one
+        let compressed = client_codec.compress(message).unwrap();
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let mut sent = frame(OpCode::Data(Data::Text), false, true, first);
+        sent.extend(frame(OpCode::Control(Control::Ping), true, false, b""));
+        sent.extend(frame(OpCode::Data(Data::Continue), true, false, second));
+        sent.extend(frame(OpCode::Data(Data::Binary), true, false, message));
+        for byte in sent {
+            client.write_all(&[byte]).await.unwrap();
+        }
+
+        let mut buf = Vec::new();
+        let (header, _) = read_frame(&mut server, &mut buf).await;
+        assert_eq!(header.opcode, OpCode::Control(Control::Ping));
+
+        let (header, payload) = read_frame(&mut server, &mut buf).await;
+        assert_eq!(header.opcode, OpCode::Data(Data::Text));
+        assert!(header.is_final);
+        assert!(header.rsv1.not());
+        assert_eq!(header.mask, Some([0; 4]));
+        assert_eq!(payload, message);
+
+        let (header, mut payload) = read_frame(&mut server, &mut buf).await;
+        assert_eq!(header.opcode, OpCode::Data(Data::Binary));
+        apply_mask(&mut payload, header.mask.unwrap());
+        assert_eq!(payload, message);
+    }
+
+    #[tokio::test]
+    async fn test_read_garbage() {
+        let (mut client, server) = duplex(64 * 1024);
+        let mut server = DeflateStream::new(server);
+        server.enable(deflate(false));
+
+        let sent = frame(OpCode::Data(Data::Text), true, true, &[0xff; 16]);
+        client.write_all(&sent).await.unwrap();
+        let error = server.read(&mut [0; 1024]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    // Through tungstenite, from the handshake on
+    // result_large_err: the handshake callback's signature is imposed by tungstenite
+    #[allow(clippy::result_large_err)]
+    #[tokio::test]
+    async fn test_websocket() {
+        let (mut client, server) = duplex(64 * 1024);
+        let config = config::WebSocket {
+            deflate: true,
+            ..config::WebSocket::default()
+        };
+        let server = tokio::spawn(async move {
+            let mut deflate = None;
+            let stream = ws::tokio::TokioAdapter::new(DeflateStream::new(server));
+            let mut ws = ws::accept_hdr_async(stream, |request: &Request, mut response| {
+                deflate = Deflate::negotiate(&config, request, &mut response);
+                Ok(response)
+            })
+            .await
+            .unwrap();
+            ws.get_mut().get_mut().enable(deflate.unwrap());
+
+            let message = ws.next().await.unwrap().unwrap();
+            ws.send(message).await.unwrap();
+        });
+
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                Connection: Upgrade\r\n\
+                Upgrade: websocket\r\n\
+                Sec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        while buf.windows(4).any(|window| window == b"\r\n\r\n").not() {
+            let mut chunk = [0; 1024];
+            let len = client.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..len]);
+        }
+        let response = String::from_utf8(buf).unwrap().to_lowercase();
+        assert!(response.contains("sec-websocket-extensions: permessage-deflate\r\n"));
+
+        // the message is echoed back compressed
+        let mut client_codec = client_codec();
+        let message = br#"{"type":"auth","data":"token"}"#;
+        let compressed = client_codec.compress(message).unwrap();
+        let sent = frame(OpCode::Data(Data::Text), true, true, &compressed);
+        client.write_all(&sent).await.unwrap();
+
+        let (header, payload) = read_frame(&mut client, &mut Vec::new()).await;
+        assert!(header.rsv1);
+        assert_eq!(header.opcode, OpCode::Data(Data::Text));
+        assert_eq!(client_codec.inflate(payload).unwrap(), message);
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use async_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    http::HeaderValue,
+    Message,
+};
+
+// How messages are encoded for a websocket client, negotiated with the
+// `Sec-WebSocket-Protocol` header during the handshake.
+// JSON is sent in text frames, the others in binary frames. Clients may always send JSON in
+// text frames, whatever the encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    const ALL: [Self; 3] = [Self::Json, Self::MessagePack, Self::Cbor];
+
+    pub const fn subprotocol(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    // Picks the first of the subprotocols offered by the client that is supported,
+    // and accepts it in the response. JSON is used when none is.
+    pub fn negotiate(request: &Request, response: &mut Response) -> Self {
+        let encoding = request
+            .headers()
+            .get_all("sec-websocket-protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|offered| {
+                Self::ALL
+                    .into_iter()
+                    .find(|encoding| encoding.subprotocol() == offered.trim())
+            });
+
+        let Some(encoding) = encoding else {
+            return Self::Json;
+        };
+
+        response.headers_mut().insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_static(encoding.subprotocol()),
+        );
+        encoding
+    }
+
+    pub fn encode<T: serde::Serialize>(self, data: &T) -> Result<Message> {
+        Ok(match self {
+            Self::Json => Message::Text(serde_json::to_string(data)?),
+            // maps rather than arrays for structs, so that the fields keep their names
+            Self::MessagePack => Message::Binary(rmp_serde::to_vec_named(data)?),
+            Self::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(data, &mut buf)?;
+                Message::Binary(buf)
+            }
+        })
+    }
+
+    // Decodes to a JSON value first, which the API types are then read from the same way
+    // whatever the encoding
+    pub fn decode(self, data: &[u8]) -> Result<serde_json::Value, String> {
+        match self {
+            Self::Json => serde_json::from_slice(data).map_err(|error| error.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(data).map_err(|error| error.to_string()),
+            Self::Cbor => ciborium::from_reader(data).map_err(|error| error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(None, Encoding::Json, None)]
+    #[case(Some("msgpack"), Encoding::MessagePack, Some("msgpack"))]
+    #[case(Some("wamp, cbor, msgpack"), Encoding::Cbor, Some("cbor"))]
+    #[case(Some("json"), Encoding::Json, Some("json"))]
+    #[case(Some("wamp"), Encoding::Json, None)]
+    fn test_negotiate(
+        #[case] offered: Option<&str>,
+        #[case] expected: Encoding,
+        #[case] expected_header: Option<&str>,
+    ) {
+        let mut request = Request::builder().uri("/");
+        if let Some(offered) = offered {
+            request = request.header("sec-websocket-protocol", offered);
+        }
+        let request = request.body(()).unwrap();
+        let mut response = Response::default();
+
+        assert_eq!(Encoding::negotiate(&request, &mut response), expected);
+        assert_eq!(
+            response
+                .headers()
+                .get("sec-websocket-protocol")
+                .map(|value| value.to_str().unwrap()),
+            expected_header
+        );
+    }
+
+    #[rstest]
+    #[case(Encoding::Json)]
+    #[case(Encoding::MessagePack)]
+    #[case(Encoding::Cbor)]
+    fn test_roundtrip(#[case] encoding: Encoding) {
+        let value = serde_json::json!({ "type": "set_subscriptions", "data": [1, 2], "id": "a" });
+
+        let data = match encoding.encode(&value).unwrap() {
+            Message::Text(data) => data.into_bytes(),
+            Message::Binary(data) => data,
+            message => panic!("unexpected message {message:?}"),
+        };
+        assert_eq!(encoding.decode(&data).unwrap(), value);
+    }
+}
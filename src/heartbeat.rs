@@ -0,0 +1,109 @@
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+
+// Most pings kept waiting for an answer, the oldest ones are forgotten first
+const MAX_OUTSTANDING: usize = 8;
+
+// Keeps track of the pings sent to a websocket client and the pongs it answered with.
+// A pong answers its ping and every ping sent before it, the client is only late while the
+// oldest of the remaining ones is.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    // Payload and time of the unanswered pings, oldest first
+    outstanding: VecDeque<([u8; 8], Instant)>,
+    // When the oldest unanswered ping was sent
+    unanswered_since: Option<Instant>,
+}
+
+impl Heartbeat {
+    // Payload of the next ping
+    pub fn ping(&mut self) -> Vec<u8> {
+        let nonce = rand::random::<u64>().to_be_bytes();
+        let now = Instant::now();
+
+        if self.outstanding.len() == MAX_OUTSTANDING {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back((nonce, now));
+        self.unanswered_since.get_or_insert(now);
+
+        nonce.to_vec()
+    }
+
+    // Round-trip time of the ping the pong answers, None if it doesn't answer any
+    pub fn pong(&mut self, data: &[u8]) -> Option<Duration> {
+        let answered = self
+            .outstanding
+            .iter()
+            .position(|(nonce, _)| data == nonce)?;
+        let (_, sent_at) = self.outstanding.drain(..=answered).next_back()?;
+
+        self.unanswered_since = self.outstanding.front().map(|&(_, sent_at)| sent_at);
+
+        Some(sent_at.elapsed())
+    }
+
+    // Whether a ping has gone unanswered for longer than `timeout`
+    pub fn timed_out(&self, timeout: Duration) -> bool {
+        self.unanswered_since
+            .is_some_and(|since| since.elapsed() > timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ops::Not;
+
+    #[test]
+    fn test_pong() {
+        let mut heartbeat = Heartbeat::default();
+
+        let first = heartbeat.ping();
+        let second = heartbeat.ping();
+        let third = heartbeat.ping();
+        assert_ne!(first, second);
+
+        assert_eq!(heartbeat.pong(b"xd"), None);
+        // answering a ping settles the older ones too
+        assert!(heartbeat.pong(&second).is_some());
+        assert_eq!(heartbeat.pong(&first), None);
+        assert_eq!(heartbeat.pong(&second), None);
+        assert!(heartbeat.pong(&third).is_some());
+        assert!(heartbeat.outstanding.is_empty());
+    }
+
+    #[test]
+    fn test_pong_forgotten() {
+        let mut heartbeat = Heartbeat::default();
+
+        let first = heartbeat.ping();
+        for _ in 0..MAX_OUTSTANDING {
+            heartbeat.ping();
+        }
+
+        assert_eq!(heartbeat.pong(&first), None);
+        assert_eq!(heartbeat.outstanding.len(), MAX_OUTSTANDING);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out() {
+        let mut heartbeat = Heartbeat::default();
+        assert!(heartbeat.timed_out(Duration::ZERO).not());
+
+        let first = heartbeat.ping();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // a new ping doesn't give the client more time
+        let second = heartbeat.ping();
+        assert!(heartbeat.timed_out(Duration::from_millis(40)));
+
+        // the client is late from the next unanswered ping on
+        heartbeat.pong(&first);
+        assert!(heartbeat.timed_out(Duration::from_millis(40)).not());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(heartbeat.timed_out(Duration::from_millis(40)));
+
+        heartbeat.pong(&second);
+        assert!(heartbeat.timed_out(Duration::ZERO).not());
+    }
+}
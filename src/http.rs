@@ -0,0 +1,405 @@
+use crate::{
+    api::UpstreamStatus,
+    auth::constant_time_eq,
+    config,
+    net::PeerAddr,
+    sse,
+    state::{ClientId, State},
+    twitter::RequestedFollows,
+};
+use anyhow::Result;
+use axum::{
+    extract::{Request, State as Extract},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+
+pub async fn listener(
+    listener: TcpListener,
+    config: config::Http,
+    state: Arc<State>,
+    events: Arc<sse::Events>,
+) -> Result<()> {
+    log::info!("http listening on {}", listener.local_addr().unwrap());
+
+    axum::serve(
+        listener,
+        router(config, state, events).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn router(config: config::Http, state: Arc<State>, events: Arc<sse::Events>) -> Router {
+    let mut router = Router::new();
+
+    if let Some(token) = config.admin_token {
+        router = router
+            .route("/status", get(status))
+            // only applies to the routes above
+            .route_layer(middleware::from_fn_with_state(
+                Arc::new(token),
+                require_token,
+            ));
+    } else {
+        log::warn!("no http admin token configured, admin endpoints are disabled");
+    }
+
+    let readiness_max_backoff = Duration::from_secs(config.readiness_max_backoff);
+
+    router = router
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route(
+            "/readyz",
+            get(move |state| async move { readyz(state, readiness_max_backoff) }),
+        );
+
+    // authenticated like websocket clients, and with its own state
+    let events = Router::new()
+        .route("/events", get(sse::events))
+        .with_state((events, state.clone()));
+
+    router.with_state(state).merge(events)
+}
+
+async fn require_token(
+    Extract(token): Extract<Arc<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+
+    if authorized {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Status {
+    // in seconds
+    uptime: f64,
+    upstream: UpstreamStatus,
+    backoff: u32,
+    // unix timestamp
+    last_tweet_at: Option<u64>,
+    clients: Vec<Client>,
+    requested_follows: RequestedFollows,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Client {
+    id: ClientId,
+    name: Option<String>,
+    addr: PeerAddr,
+    // unix timestamp
+    connected_at: u64,
+    follows: usize,
+    // round-trip time of the latest ping in seconds, null until the first pong
+    rtt: Option<f64>,
+}
+
+async fn status(Extract(state): Extract<Arc<State>>) -> Json<Status> {
+    let mut clients: Vec<_> = state
+        .clients()
+        .into_iter()
+        .map(|(id, client)| Client {
+            id,
+            name: client.name,
+            addr: client.addr,
+            connected_at: unix_timestamp(client.connected_at),
+            follows: client.follows,
+            rtt: client.rtt.as_ref().map(Duration::as_secs_f64),
+        })
+        .collect();
+    clients.sort_by_key(|client| client.id);
+
+    Json(Status {
+        uptime: state.uptime().as_secs_f64(),
+        upstream: state.upstream_status(),
+        backoff: state.backoff(),
+        last_tweet_at: state.last_tweet_at().map(unix_timestamp),
+        clients,
+        requested_follows: state.requested_follows(),
+    })
+}
+
+async fn metrics(Extract(state): Extract<Arc<State>>) -> impl IntoResponse {
+    // computed on scrape, nothing else would keep it up to date
+    state
+        .metrics
+        .last_upstream_message_age_seconds
+        .set(state.last_upstream_message_age().as_secs_f64());
+
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        state.metrics.encode(),
+    )
+}
+
+// Whether the process is alive and accepting websocket connections
+async fn healthz(Extract(state): Extract<Arc<State>>) -> (StatusCode, &'static str) {
+    if state.websocket_listening() {
+        (StatusCode::OK, "ok")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "websocket listener is down",
+        )
+    }
+}
+
+// Whether tweets are flowing, or nothing is requested
+fn readyz(
+    Extract(state): Extract<Arc<State>>,
+    readiness_max_backoff: Duration,
+) -> (StatusCode, String) {
+    let down_for = state.upstream_down_for();
+
+    if down_for <= readiness_max_backoff {
+        (StatusCode::OK, "ready".into())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("twitter stream has been down for {down_for:?}"),
+        )
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{auth::Auth, quota::Quota, Follows};
+    use axum::{
+        body::{to_bytes, Body},
+        extract::ConnectInfo,
+    };
+    use futures::StreamExt;
+    use rstest::rstest;
+    use tokio::sync::{broadcast, mpsc, watch};
+    use tower::ServiceExt;
+
+    fn state() -> Arc<State> {
+        Arc::new(State::new(watch::channel(UpstreamStatus::Stopped).1))
+    }
+
+    fn events(tx_requested_follows: mpsc::Sender<(ClientId, Follows)>) -> Arc<sse::Events> {
+        Arc::new(sse::Events {
+            tx_requested_follows,
+            tx_tweet: broadcast::channel(1).0,
+            rx_upstream_status: watch::channel(UpstreamStatus::Stopped).1,
+            auth: Arc::new(Auth::default()),
+            quota: Arc::new(Quota::new(5000, 5000)),
+            keep_alive: Duration::from_secs(30),
+        })
+    }
+
+    fn router_with_events(
+        admin_token: Option<&str>,
+        state: Arc<State>,
+        events: Arc<sse::Events>,
+    ) -> Router {
+        let config = config::Http {
+            admin_token: admin_token.map(String::from),
+            ..config::Http::default()
+        };
+
+        super::router(config, state, events)
+    }
+
+    fn router(admin_token: Option<&str>, state: Arc<State>) -> Router {
+        router_with_events(admin_token, state, events(mpsc::channel(1).0))
+    }
+
+    async fn get(router: Router, uri: &str, authorization: Option<&str>) -> (StatusCode, Vec<u8>) {
+        let mut request = Request::get(uri);
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        let response = router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[rstest]
+    #[case(Some("Bearer hunter2"), StatusCode::OK)]
+    #[case(Some("Bearer hunter3"), StatusCode::UNAUTHORIZED)]
+    #[case(Some("Bearer hunter"), StatusCode::UNAUTHORIZED)]
+    #[case(Some("Bearer hunter22"), StatusCode::UNAUTHORIZED)]
+    #[case(Some("hunter2"), StatusCode::UNAUTHORIZED)]
+    #[case(Some("Basic aHVudGVyMg=="), StatusCode::UNAUTHORIZED)]
+    #[case(None, StatusCode::UNAUTHORIZED)]
+    #[tokio::test]
+    async fn test_status_auth(#[case] authorization: Option<&str>, #[case] expected: StatusCode) {
+        let (status, _) = get(router(Some("hunter2"), state()), "/status", authorization).await;
+        assert_eq!(status, expected);
+    }
+
+    #[tokio::test]
+    async fn test_status_disabled() {
+        let (status, _) = get(router(None, state()), "/status", Some("Bearer hunter2")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let state = state();
+        let id = state.new_client_id();
+        state.client_connected(id, PeerAddr::Tcp(([127, 0, 0, 1], 4000).into()));
+        state.client_named(id, "pajbot");
+        state.client_follows_changed(id, 2);
+        state.client_rtt_measured(id, Duration::from_millis(250));
+        state.set_requested_follows(&RequestedFollows::from([(1, [id].into())]));
+
+        let (status, body) = get(
+            router(Some("hunter2"), state),
+            "/status",
+            Some("Bearer hunter2"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(status["uptime"].is_f64());
+        assert!(status["clients"][0]["connected_at"].is_u64());
+        status["uptime"] = 0.into();
+        status["clients"][0]["connected_at"] = 0.into();
+        assert_eq!(
+            status,
+            serde_json::json!({
+                "uptime": 0,
+                "upstream": { "state": "stopped" },
+                "backoff": 0,
+                "last_tweet_at": null,
+                "clients": [{
+                    "id": 0,
+                    "name": "pajbot",
+                    "addr": "127.0.0.1:4000",
+                    "connected_at": 0,
+                    "follows": 2,
+                    "rtt": 0.25,
+                }],
+                "requested_follows": { "1": [0] },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let state = state();
+        let (status, _) = get(router(None, state.clone()), "/healthz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        state.set_websocket_listening(true);
+        let (status, _) = get(router(None, state.clone()), "/healthz", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        state.set_websocket_listening(false);
+        let (status, _) = get(router(None, state), "/healthz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[rstest]
+    // nothing requested is zero downtime, which is within any backoff
+    #[case(false, Duration::ZERO, StatusCode::OK)]
+    #[case(true, Duration::ZERO, StatusCode::SERVICE_UNAVAILABLE)]
+    #[case(true, Duration::from_millis(1), StatusCode::SERVICE_UNAVAILABLE)]
+    #[case(true, Duration::from_mins(1), StatusCode::OK)]
+    #[tokio::test]
+    async fn test_readyz(
+        #[case] requested: bool,
+        #[case] readiness_max_backoff: Duration,
+        #[case] expected: StatusCode,
+    ) {
+        let state = state();
+        if requested {
+            state.set_requested_follows(&RequestedFollows::from([(1, [ClientId(0)].into())]));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (status, _) = readyz(Extract(state), readiness_max_backoff);
+        assert_eq!(status, expected);
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let state = state();
+        let (tx_requested_follows, mut rx_requested_follows) = mpsc::channel(1);
+        let events = events(tx_requested_follows);
+
+        let mut request = Request::get("/events?follow=1&name=pajbot")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let response = router_with_events(None, state.clone(), events.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        // registered with the supervisor before the stream starts
+        let (id, follows) = rx_requested_follows.recv().await.unwrap();
+        assert_eq!(follows, Follows::from([1]));
+        assert_eq!(state.clients()[&id].name.as_deref(), Some("pajbot"));
+        assert_eq!(state.clients()[&id].follows, 1);
+
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while received.matches("\n\n").count() < 2 {
+            let frame = body.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&frame).unwrap());
+        }
+        assert_eq!(
+            received,
+            concat!(
+                "data: {\"type\":\"upstream_status\",\"data\":{\"state\":\"stopped\"}}\n\n",
+                "data: {\"type\":\"ack_subscriptions\",\"data\":[1]}\n\n",
+            )
+        );
+
+        // the client is unsubscribed once it goes away
+        drop(body);
+        let (unsubscribed, follows) = rx_requested_follows.recv().await.unwrap();
+        assert_eq!(unsubscribed, id);
+        assert!(follows.is_empty());
+        assert!(state.clients().is_empty());
+
+        // and its follows no longer count towards the total
+        let (_, refused) = events.quota.request(&Follows::new(), (2..=5001).collect());
+        assert!(refused.is_empty());
+    }
+}
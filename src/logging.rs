@@ -0,0 +1,141 @@
+use crate::config::{Args, LogFormat, LogTimestamps};
+use log::{kv, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use simple_logger::SimpleLogger;
+use std::{io::Write, ops::Not};
+
+pub fn init(args: &Args) -> Result<(), log::SetLoggerError> {
+    match args.log_format {
+        LogFormat::Text => {
+            let mut logger = SimpleLogger::new().with_level(args.log_level);
+            logger = match args.log_timestamps {
+                LogTimestamps::Local => logger.with_local_timestamps(),
+                LogTimestamps::UTC => logger.with_utc_timestamps(),
+                LogTimestamps::Off => logger.without_timestamps(),
+            };
+            logger.init()
+        }
+
+        LogFormat::Json => JsonLogger::new(args.log_level, args.log_timestamps).init(),
+    }
+}
+
+// Writes one JSON object per line, with the key-values of the log call as fields.
+// simple_logger takes care of the text format, but drops the key-values.
+pub struct JsonLogger {
+    level: LevelFilter,
+    timestamps: LogTimestamps,
+}
+
+impl JsonLogger {
+    pub const fn new(level: LevelFilter, timestamps: LogTimestamps) -> Self {
+        Self { level, timestamps }
+    }
+
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    fn timestamp(&self) -> Option<String> {
+        use chrono::SecondsFormat;
+
+        match self.timestamps {
+            LogTimestamps::Local => {
+                Some(chrono::Local::now().to_rfc3339_opts(SecondsFormat::Millis, false))
+            }
+            LogTimestamps::UTC => {
+                Some(chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+            LogTimestamps::Off => None,
+        }
+    }
+
+    fn fields(&self, record: &Record) -> Map<String, Value> {
+        let mut fields = Map::new();
+
+        if let Some(timestamp) = self.timestamp() {
+            fields.insert("timestamp".into(), timestamp.into());
+        }
+        fields.insert("level".into(), record.level().as_str().into());
+        fields.insert("target".into(), record.target().into());
+        fields.insert("message".into(), record.args().to_string().into());
+
+        // can't fail, our visitor never returns an error
+        let _ = record.key_values().visit(&mut FieldVisitor(&mut fields));
+
+        fields
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()).not() {
+            return;
+        }
+
+        let fields = self.fields(record);
+
+        let mut stdout = std::io::stdout().lock();
+        let _ = serde_json::to_writer(&mut stdout, &fields);
+        let _ = writeln!(stdout);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> kv::VisitSource<'kvs> for FieldVisitor<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(value).unwrap_or_else(|error| error.to_string().into());
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+    use serde_json::json;
+
+    #[test]
+    fn test_fields() {
+        let logger = JsonLogger::new(LevelFilter::Info, LogTimestamps::Off);
+        let key_values = [("client", "pajbot"), ("error", "connection reset")];
+        let fields = logger.fields(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("tweet_provider::websocket")
+                .args(format_args!("client {} disconnected", 3))
+                .key_values(&key_values)
+                .build(),
+        );
+
+        assert_eq!(
+            Value::Object(fields),
+            json!({
+                "level": "WARN",
+                "target": "tweet_provider::websocket",
+                "message": "client 3 disconnected",
+                "client": "pajbot",
+                "error": "connection reset",
+            })
+        );
+    }
+
+    #[test]
+    fn test_fields_timestamp() {
+        let logger = JsonLogger::new(LevelFilter::Info, LogTimestamps::UTC);
+        let fields = logger.fields(&Record::builder().args(format_args!("starting")).build());
+        assert!(fields["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(fields["message"], "starting");
+    }
+}
@@ -6,19 +6,39 @@
 #![warn(clippy::cargo)]
 #![allow(clippy::upper_case_acronyms)]
 #![allow(clippy::redundant_pub_crate)]
+// egg-mode pins older hyper, http, hmac and base64 than the rest of the tree
+#![allow(clippy::multiple_crate_versions)]
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use config::{Config, LogTimestamps};
-use simple_logger::SimpleLogger;
-use std::{collections::HashSet, sync::Arc};
+use config::Config;
+use std::{collections::HashSet, ops::Not, sync::Arc, time::Duration};
 use tokio::{
     net::TcpListener,
-    sync::{broadcast, mpsc, Notify},
+    sync::{broadcast, mpsc, watch, Notify},
 };
 
 mod api;
+mod auth;
 mod config;
+mod deflate;
+mod encoding;
+mod heartbeat;
+mod http;
+mod logging;
+mod metrics;
+mod net;
+mod queue;
+mod quota;
+mod rate_limit;
+mod session;
+mod sinks;
+mod sse;
+mod state;
+mod subscriptions;
+#[cfg(feature = "otlp")]
+mod telemetry;
+mod tls;
 mod twitter;
 mod websocket;
 
@@ -29,12 +49,15 @@ const TWEET_CHANNEL_CAPACITY: usize = 16;
 
 fn main() {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let mut failure = false;
-
-    if let Err(error) = rt.block_on(run()) {
+    let failure = if let Err(error) = rt.block_on(run()) {
         log::error!("fatal: {:#}", error);
-        failure = true;
-    }
+        true
+    } else {
+        false
+    };
+
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown();
 
     log::info!("waiting one second for tasks to end");
     rt.shutdown_timeout(std::time::Duration::from_secs(1));
@@ -47,21 +70,19 @@ fn main() {
     log::info!("exiting");
 }
 
+#[allow(clippy::too_many_lines)]
 async fn run() -> Result<()> {
     let args = config::Args::parse();
 
     if std::env::var_os("TWEET_PROVIDER_DUMP_ARGS_AND_EXIT").is_some() {
-        println!("{:#?}", args);
+        println!("{args:#?}");
         return Ok(());
     }
 
-    let mut logger = SimpleLogger::new().with_level(args.log_level);
-    logger = match args.log_timestamps {
-        LogTimestamps::Local => logger.with_local_timestamps(),
-        LogTimestamps::UTC => logger.with_utc_timestamps(),
-        LogTimestamps::Off => logger.without_timestamps(),
-    };
-    logger.init()?;
+    logging::init(&args)?;
+
+    #[cfg(feature = "otlp")]
+    telemetry::init().context("failed to set up the otlp exporter")?;
 
     let config = match Config::from_toml(&args.config_path).await {
         Ok(config) => args.config.merge(config),
@@ -76,7 +97,7 @@ async fn run() -> Result<()> {
     };
 
     if std::env::var_os("TWEET_PROVIDER_DUMP_CONFIG_AND_EXIT").is_some() {
-        println!("{:#?}", config);
+        println!("{config:#?}");
         return Ok(());
     }
 
@@ -93,6 +114,92 @@ async fn run() -> Result<()> {
         "- websocket listen address: {}",
         config.websocket.listen_addr
     );
+    let auth = Arc::new(auth::Auth::load(&config.websocket).await?);
+    if auth.is_enabled() {
+        log::info!("- websocket authentication: {} tokens", auth.len());
+    } else {
+        log::warn!("- websocket authentication: disabled, anyone can connect");
+    }
+    log::info!(
+        "- websocket exit message: {}",
+        if config.websocket.disable_exit {
+            "disabled"
+        } else if auth.is_enabled() {
+            "admins only"
+        } else {
+            "anyone"
+        }
+    );
+    let sessions = Arc::new(session::Sessions::new(Duration::from_secs(
+        config.websocket.session_grace_period,
+    )));
+    if sessions.is_enabled() {
+        log::info!(
+            "- websocket sessions: kept for {:?} after a disconnect",
+            sessions.grace_period()
+        );
+    } else {
+        log::info!("- websocket sessions: disabled");
+    }
+    let quota = Arc::new(quota::Quota::new(
+        config.websocket.max_follows_per_client,
+        config.websocket.max_follows,
+    ));
+    log::info!(
+        "- websocket follow limits: {} per client, {} in total",
+        config.websocket.max_follows_per_client,
+        config.websocket.max_follows
+    );
+    let tls = tls::Tls::load(&config.websocket)
+        .await
+        .context("failed to load websocket tls certificate")?
+        .map(Arc::new);
+    log::info!(
+        "- websocket tls: {}",
+        if tls.is_some() { "enabled" } else { "disabled" }
+    );
+    anyhow::ensure!(
+        config.websocket.deflate_level <= 9,
+        "websocket deflate level must be between 0 and 9"
+    );
+    if config.websocket.deflate {
+        log::info!(
+            "- websocket compression: permessage-deflate at level {}",
+            config.websocket.deflate_level
+        );
+    } else {
+        log::info!("- websocket compression: disabled");
+    }
+    log::info!(
+        "- http listen address: {}",
+        config
+            .http
+            .listen_addr
+            .map_or_else(|| "disabled".into(), |addr| addr.to_string())
+    );
+    log::info!("- webhooks: {}", config.webhooks.len());
+    log::info!(
+        "- static subscriptions: {} users, {} handles",
+        config
+            .subscriptions
+            .iter()
+            .map(|subscription| subscription.follows.len())
+            .sum::<usize>(),
+        config
+            .subscriptions
+            .iter()
+            .map(|subscription| subscription.handles.len())
+            .sum::<usize>()
+    );
+    let sink_names = config.sinks.names();
+    log::info!(
+        "- sinks: {}",
+        if sink_names.is_empty() {
+            "none".into()
+        } else {
+            sink_names.join(", ")
+        }
+    );
     log::info!(
         "- always restart twitter consumer: {}",
         config.twitter.always_restart
@@ -105,18 +212,87 @@ async fn run() -> Result<()> {
     // - attempt #1: ownership issues in twitter::supervisor
     let (tx_tweet, _) = broadcast::channel(TWEET_CHANNEL_CAPACITY);
 
+    let (tx_upstream_status, rx_upstream_status) = watch::channel(api::UpstreamStatus::Stopped);
+
+    let state = Arc::new(state::State::new(rx_upstream_status.clone()));
+
     let lifeline = Arc::new(Notify::new());
 
+    let events = Arc::new(sse::Events {
+        tx_requested_follows: tx_requested_follows.clone(),
+        tx_tweet: tx_tweet.clone(),
+        rx_upstream_status: rx_upstream_status.clone(),
+        auth: auth.clone(),
+        quota: quota.clone(),
+        keep_alive: Duration::from_secs(config.http.sse_keep_alive.max(1)),
+    });
+
     log::info!("starting");
 
+    sinks::spawn(
+        &config,
+        &sinks::Context {
+            tx_tweet: tx_tweet.clone(),
+            tx_requested_follows: tx_requested_follows.clone(),
+            state: state.clone(),
+            quota: quota.clone(),
+        },
+    )
+    .await?;
+
+    if config.subscriptions.is_empty().not() {
+        tokio::spawn(subscriptions::subscribe(
+            config.subscriptions,
+            config.twitter.token(),
+            state.new_client_id(),
+            tx_requested_follows.clone(),
+            state.clone(),
+            quota.clone(),
+        ));
+    }
+
     let websocket_listener = websocket::listener(
-        TcpListener::bind(config.websocket.listen_addr).await?,
+        net::Listener::bind(
+            &config.websocket.listen_addr,
+            config.websocket.unix_socket_mode,
+        )
+        .await?,
+        Arc::new(config.websocket.clone()),
         tx_requested_follows,
         tx_tweet.clone(),
+        rx_upstream_status,
+        &state,
+        &auth,
+        &sessions,
+        &quota,
+        tls.as_ref(),
         &lifeline,
     );
 
-    let twitter_supervisor = twitter::supervisor(config.twitter, rx_requested_follows, tx_tweet);
+    let tls_reloader = async {
+        match &tls {
+            Some(tls) => tls.reloader().await,
+            None => futures::future::pending().await,
+        }
+    };
+
+    let twitter_supervisor = twitter::supervisor(
+        config.twitter,
+        rx_requested_follows,
+        tx_tweet,
+        tx_upstream_status,
+        state.clone(),
+    );
+
+    let http_listener = async {
+        match config.http.listen_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                http::listener(listener, config.http, state.clone(), events).await
+            }
+            None => futures::future::pending().await,
+        }
+    };
 
     tokio::select! {
         res = websocket_listener => {
@@ -127,7 +303,15 @@ async fn run() -> Result<()> {
             res.context("twitter supervisor stopped")?;
         }
 
-        _ = lifeline.notified() => {
+        res = http_listener => {
+            res.context("http listener stopped")?;
+        }
+
+        res = tls_reloader => {
+            res.context("tls reloader stopped")?;
+        }
+
+        () = lifeline.notified() => {
             log::info!("lifeline cut, shutting down");
         }
 
@@ -0,0 +1,216 @@
+use prometheus::{
+    Encoder, Gauge, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+// Prometheus metrics, exported by the HTTP listener on `/metrics`
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub tweets_received: IntCounter,
+    pub tweets_delivered: IntCounterVec,
+    pub broadcast_lagged: IntCounter,
+    pub tweets_dropped: IntCounter,
+    pub slow_client_disconnects: IntCounter,
+    pub stream_restarts: IntCounterVec,
+    pub backoff: IntGauge,
+    pub backoff_seconds: Gauge,
+    pub connected_clients: IntGauge,
+    pub follows: IntGauge,
+    pub follows_refused: IntCounter,
+    pub protocol_errors: IntCounter,
+    pub rate_limited: IntCounter,
+    pub rate_limit_disconnects: IntCounter,
+    pub webhook_retries: IntCounter,
+    pub sink_messages: IntCounterVec,
+    pub sink_reconnects: IntCounterVec,
+    pub last_upstream_message_age_seconds: Gauge,
+}
+
+impl Metrics {
+    #[allow(clippy::too_many_lines)]
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("tweet_provider".into()), None).unwrap();
+
+        let metrics = Self {
+            tweets_received: IntCounter::new(
+                "tweets_received_total",
+                "Tweets received from the twitter stream",
+            )
+            .unwrap(),
+            tweets_delivered: IntCounterVec::new(
+                Opts::new(
+                    "tweets_delivered_total",
+                    "Tweets sent to a websocket client, removed when the client disconnects",
+                ),
+                &["client"],
+            )
+            .unwrap(),
+            broadcast_lagged: IntCounter::new(
+                "broadcast_lagged_total",
+                "Times a websocket client fell behind and skipped tweets",
+            )
+            .unwrap(),
+            tweets_dropped: IntCounter::new(
+                "tweets_dropped_total",
+                "Tweets dropped from the queue of a slow websocket client",
+            )
+            .unwrap(),
+            slow_client_disconnects: IntCounter::new(
+                "slow_client_disconnects_total",
+                "Websocket clients disconnected because their queue was full",
+            )
+            .unwrap(),
+            stream_restarts: IntCounterVec::new(
+                Opts::new(
+                    "stream_restarts_total",
+                    "Twitter stream restarts caused by an error",
+                ),
+                &["error_kind"],
+            )
+            .unwrap(),
+            backoff: IntGauge::new("backoff", "Current backoff exponent").unwrap(),
+            backoff_seconds: Gauge::new(
+                "backoff_seconds",
+                "Delay before the twitter stream restarts, 0 when not backing off",
+            )
+            .unwrap(),
+            connected_clients: IntGauge::new("connected_clients", "Connected websocket clients")
+                .unwrap(),
+            follows: IntGauge::new("follows", "Twitter users requested by clients").unwrap(),
+            follows_refused: IntCounter::new(
+                "follows_refused_total",
+                "Follows refused because a client or all of them were over the follow limits",
+            )
+            .unwrap(),
+            protocol_errors: IntCounter::new(
+                "websocket_protocol_errors_total",
+                "Messages from websocket clients that could not be decoded",
+            )
+            .unwrap(),
+            rate_limited: IntCounter::new(
+                "websocket_rate_limited_total",
+                "Messages from websocket clients ignored for being over the rate limit",
+            )
+            .unwrap(),
+            rate_limit_disconnects: IntCounter::new(
+                "websocket_rate_limit_disconnects_total",
+                "Websocket clients disconnected for repeatedly going over the rate limit",
+            )
+            .unwrap(),
+            webhook_retries: IntCounter::new(
+                "webhook_retries_total",
+                "Webhook deliveries attempted again after a failure",
+            )
+            .unwrap(),
+            sink_messages: IntCounterVec::new(
+                Opts::new(
+                    "sink_messages_total",
+                    "Tweets handled by a webhook or sink, by result: delivered, failed or dropped",
+                ),
+                &["sink", "result"],
+            )
+            .unwrap(),
+            sink_reconnects: IntCounterVec::new(
+                Opts::new(
+                    "sink_reconnects_total",
+                    "Times an output sink lost or failed to open its connection",
+                ),
+                &["sink"],
+            )
+            .unwrap(),
+            last_upstream_message_age_seconds: Gauge::new(
+                "last_upstream_message_age_seconds",
+                "Time since the twitter stream last sent anything, or since startup",
+            )
+            .unwrap(),
+            registry,
+        };
+
+        for collector in [
+            Box::new(metrics.tweets_received.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.tweets_delivered.clone()),
+            Box::new(metrics.broadcast_lagged.clone()),
+            Box::new(metrics.tweets_dropped.clone()),
+            Box::new(metrics.slow_client_disconnects.clone()),
+            Box::new(metrics.stream_restarts.clone()),
+            Box::new(metrics.backoff.clone()),
+            Box::new(metrics.backoff_seconds.clone()),
+            Box::new(metrics.connected_clients.clone()),
+            Box::new(metrics.follows.clone()),
+            Box::new(metrics.follows_refused.clone()),
+            Box::new(metrics.protocol_errors.clone()),
+            Box::new(metrics.rate_limited.clone()),
+            Box::new(metrics.rate_limit_disconnects.clone()),
+            Box::new(metrics.webhook_retries.clone()),
+            Box::new(metrics.sink_messages.clone()),
+            Box::new(metrics.sink_reconnects.clone()),
+            Box::new(metrics.last_upstream_message_age_seconds.clone()),
+        ] {
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+
+    // Renders every metric in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{collections::BTreeSet, ops::Not};
+
+    // Names listed under "### Metrics" in the README
+    fn documented() -> BTreeSet<String> {
+        include_str!("../README.md")
+            .split("### Metrics")
+            .nth(1)
+            .unwrap()
+            .lines()
+            .skip_while(|line| line.starts_with("- ").not())
+            .take_while(|line| line.starts_with("- "))
+            .map(|line| {
+                let name = line.trim_start_matches("- `");
+                let end = name.find(['`', '{']).unwrap();
+                format!("tweet_provider_{}", &name[..end])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+
+        // vectors are only exported once they have a child
+        metrics.tweets_delivered.with_label_values(&["0"]).inc();
+        metrics
+            .stream_restarts
+            .with_label_values(&["net_error"])
+            .inc();
+        metrics
+            .sink_messages
+            .with_label_values(&["redis", "delivered"])
+            .inc();
+        metrics.sink_reconnects.with_label_values(&["redis"]).inc();
+
+        let encoded = metrics.encode();
+        let exported: BTreeSet<String> = encoded
+            .lines()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .map(|line| line.split(' ').next().unwrap().to_owned())
+            .collect();
+
+        assert_eq!(exported, documented());
+        assert!(encoded
+            .contains(r#"tweet_provider_sink_messages_total{result="delivered",sink="redis"} 1"#));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::config::ListenAddr;
+use anyhow::{Context, Result};
+use async_tungstenite::tungstenite::http::Request;
+use std::{borrow::Cow, fmt, net::SocketAddr, path::Path};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+// What the websocket runs over: TCP, unix socket or TLS on top of either
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+// Where a client connected from, unix socket clients have no address of their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+impl serde::Serialize for PeerAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Percent-decoded value of the first `key` parameter in the request's query string.
+// `+` is kept as is, tokens may contain it.
+pub fn query_param<B>(request: &Request<B>, key: &str) -> Option<String> {
+    let value = request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))?;
+
+    percent_encoding::percent_decode_str(value)
+        .decode_utf8()
+        .ok()
+        .map(Cow::into_owned)
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+impl Listener {
+    // `mode` sets the permissions of unix sockets, TCP listeners ignore it
+    pub async fn bind(addr: &ListenAddr, mode: Option<u32>) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                if let Ok(metadata) = tokio::fs::symlink_metadata(path).await {
+                    anyhow::ensure!(
+                        metadata.file_type().is_socket(),
+                        "{} exists and isn't a socket",
+                        path.display()
+                    );
+                    anyhow::ensure!(
+                        tokio::net::UnixStream::connect(path).await.is_err(),
+                        "{} is already in use",
+                        path.display()
+                    );
+
+                    // left behind by a previous run that didn't get to clean up
+                    tokio::fs::remove_file(path).await?;
+                }
+
+                let listener = match mode {
+                    Some(mode) => bind_unix_with_mode(path, mode).await,
+                    None => tokio::net::UnixListener::bind(path).map_err(Into::into),
+                }
+                .with_context(|| format!("binding {}", path.display()))?;
+
+                Ok(Self::Unix(listener, path.clone()))
+            }
+
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                anyhow::bail!("unix sockets are not supported on this platform")
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(Box<dyn Stream>, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Tcp(addr)))
+            }
+
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Unix))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> String {
+        match self {
+            Self::Tcp(listener) => listener
+                .local_addr()
+                .map_or_else(|_| "unknown".into(), |addr| addr.to_string()),
+
+            #[cfg(unix)]
+            Self::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+// Binds the socket inside a private directory and only moves it to `path` once its mode is set,
+// so that nobody can connect to it before that
+#[cfg(unix)]
+async fn bind_unix_with_mode(path: &Path, mode: u32) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let file_name = path.file_name().context("no file name")?.to_string_lossy();
+    let dir = path.with_file_name(format!(".{file_name}.{}", std::process::id()));
+    let private = dir.join("socket");
+
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("creating {}", dir.display()))?;
+
+    let listener = async {
+        let listener = tokio::net::UnixListener::bind(&private)?;
+        tokio::fs::set_permissions(&private, std::fs::Permissions::from_mode(mode))
+            .await
+            .context("setting permissions")?;
+        tokio::fs::rename(&private, path).await?;
+        Ok(listener)
+    }
+    .await;
+
+    let _ = tokio::fs::remove_file(&private).await;
+    let _ = tokio::fs::remove_dir(&dir).await;
+    listener
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::{ops::Not, os::unix::fs::PermissionsExt};
+
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("tweet-provider-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_mode() {
+        let path = socket_path("mode");
+        let listener = Listener::bind(&ListenAddr::Unix(path.clone()), Some(0o600))
+            .await
+            .unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+
+        drop(listener);
+        assert!(path.exists().not());
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_in_use() {
+        let path = socket_path("in-use");
+        let _listener = Listener::bind(&ListenAddr::Unix(path.clone()), None)
+            .await
+            .unwrap();
+
+        let error = Listener::bind(&ListenAddr::Unix(path.clone()), None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("{} is already in use", path.display())
+        );
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_stale() {
+        let path = socket_path("stale");
+        // a listener that is gone without removing its socket
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let _listener = Listener::bind(&ListenAddr::Unix(path.clone()), None)
+            .await
+            .unwrap();
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+    }
+}
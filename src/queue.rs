@@ -0,0 +1,120 @@
+use crate::config::SlowClientPolicy;
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::sync::Notify;
+
+// Tweets waiting to be written to a websocket client.
+// Filled as tweets are broadcast and emptied as fast as the client reads them, so that a slow
+// client only holds up itself.
+#[derive(Debug)]
+pub struct Queue<T> {
+    capacity: usize,
+    policy: SlowClientPolicy,
+    inner: Mutex<Inner<T>>,
+    notify: Notify,
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    items: VecDeque<T>,
+    // Dropped since the client was last told, only counted with the notify policy
+    dropped: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    // The queue was full, the oldest item was dropped to make room
+    DroppedOldest,
+    // The queue was full and the policy is to disconnect, nothing was queued
+    Full,
+}
+
+impl<T> Queue<T> {
+    pub fn new(capacity: usize, policy: SlowClientPolicy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            policy,
+            inner: Mutex::new(Inner {
+                items: VecDeque::new(),
+                dropped: 0,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, item: T) -> Pushed {
+        let mut inner = self.inner.lock().unwrap();
+
+        let pushed = if inner.items.len() < self.capacity {
+            Pushed::Queued
+        } else if self.policy == SlowClientPolicy::Disconnect {
+            return Pushed::Full;
+        } else {
+            inner.items.pop_front();
+            Pushed::DroppedOldest
+        };
+
+        inner.items.push_back(item);
+        drop(inner);
+
+        if pushed == Pushed::DroppedOldest {
+            self.count_dropped(1);
+        }
+        self.notify.notify_one();
+
+        pushed
+    }
+
+    // For items that were lost before reaching the queue
+    pub fn count_dropped(&self, count: u64) {
+        if self.policy == SlowClientPolicy::Notify {
+            self.inner.lock().unwrap().dropped += count;
+        }
+    }
+
+    // Waits for the next item, along with how many were dropped since the last one
+    pub async fn pop(&self) -> (u64, T) {
+        loop {
+            if let Some(popped) = self.try_pop() {
+                return popped;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<(u64, T)> {
+        let mut inner = self.inner.lock().unwrap();
+        let item = inner.items.pop_front()?;
+        Some((std::mem::take(&mut inner.dropped), item))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(SlowClientPolicy::DropOldest, [Pushed::Queued, Pushed::Queued, Pushed::DroppedOldest], vec![(0, 2), (0, 3)])]
+    #[case(SlowClientPolicy::Notify, [Pushed::Queued, Pushed::Queued, Pushed::DroppedOldest], vec![(1, 2), (0, 3)])]
+    #[case(SlowClientPolicy::Disconnect, [Pushed::Queued, Pushed::Queued, Pushed::Full], vec![(0, 1), (0, 2)])]
+    #[tokio::test]
+    async fn test_queue(
+        #[case] policy: SlowClientPolicy,
+        #[case] expected_pushed: [Pushed; 3],
+        #[case] expected_popped: Vec<(u64, u32)>,
+    ) {
+        let queue = Queue::new(2, policy);
+
+        let pushed = [1, 2, 3].map(|item| queue.push(item));
+        assert_eq!(pushed, expected_pushed);
+
+        let mut popped = Vec::new();
+        for _ in 0..expected_popped.len() {
+            popped.push(queue.pop().await);
+        }
+        assert_eq!(popped, expected_popped);
+        assert!(queue.try_pop().is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use crate::Follows;
+use std::{collections::HashMap, ops::Not, sync::Mutex};
+
+// Limits on the follows requested from twitter, by each client and by all of them together.
+// Kept alongside the follows the clients send to the supervisor, so that requests over the
+// limits are refused before they reach the twitter stream.
+#[derive(Debug)]
+pub struct Quota {
+    per_client: usize,
+    total: usize,
+    // How many clients requested each user id, parked sessions included
+    requested: Mutex<HashMap<u64, usize>>,
+}
+
+impl Quota {
+    pub fn new(per_client: usize, total: usize) -> Self {
+        Self {
+            per_client,
+            total,
+            requested: Mutex::default(),
+        }
+    }
+
+    // Swaps a client's `old` follows for as much of `new` as the limits allow.
+    // Returns the granted follows and the refused ones, follows the client already had are
+    // always granted and new ones are granted in ascending order until a limit is reached.
+    pub fn request(&self, old: &Follows, new: Follows) -> (Follows, Follows) {
+        let removed: Vec<u64> = old.difference(&new).copied().collect();
+        let (mut granted, added): (Follows, Follows) =
+            new.into_iter().partition(|id| old.contains(id));
+        let mut added: Vec<u64> = added.into_iter().collect();
+        added.sort_unstable();
+
+        let mut requested = self.requested.lock().unwrap();
+
+        // make room first, dropped follows may free up space in the total
+        for id in removed {
+            release(&mut requested, id);
+        }
+
+        let mut refused = Follows::new();
+        for id in added {
+            let over_total = requested.contains_key(&id).not() && requested.len() >= self.total;
+            if granted.len() >= self.per_client || over_total {
+                refused.insert(id);
+                continue;
+            }
+
+            *requested.entry(id).or_default() += 1;
+            granted.insert(id);
+        }
+        drop(requested);
+
+        (granted, refused)
+    }
+
+    // For follows that are no longer requested, once a client is gone for good
+    pub fn release(&self, follows: &Follows) {
+        let mut requested = self.requested.lock().unwrap();
+        for id in follows {
+            release(&mut requested, *id);
+        }
+    }
+}
+
+fn release(requested: &mut HashMap<u64, usize>, id: u64) {
+    if let Some(count) = requested.get_mut(&id) {
+        *count -= 1;
+        if *count == 0 {
+            requested.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    // under both limits
+    #[case(&[], &[1, 2], &[1, 2], &[])]
+    // over the per client limit, the lowest new ids are granted
+    #[case(&[], &[4, 3, 2, 1], &[1, 2, 3], &[4])]
+    // follows the client already had come first
+    #[case(&[4], &[4, 3, 2, 1], &[1, 2, 4], &[3])]
+    // removed follows free up space
+    #[case(&[1, 2, 3], &[3, 4, 5], &[3, 4, 5], &[])]
+    fn test_request_per_client(
+        #[case] old: &[u64],
+        #[case] new: &[u64],
+        #[case] expected_granted: &[u64],
+        #[case] expected_refused: &[u64],
+    ) {
+        let quota = Quota::new(3, 10);
+
+        let (old, refused) = quota.request(&Follows::new(), old.iter().copied().collect());
+        assert!(refused.is_empty());
+
+        let (granted, refused) = quota.request(&old, new.iter().copied().collect());
+        assert_eq!(granted, expected_granted.iter().copied().collect());
+        assert_eq!(refused, expected_refused.iter().copied().collect());
+    }
+
+    #[test]
+    fn test_request_total() {
+        let quota = Quota::new(3, 4);
+
+        let (first, _) = quota.request(&Follows::new(), Follows::from([1, 2, 3]));
+
+        // ids that are already requested don't count against the total
+        let (second, refused) = quota.request(&Follows::new(), Follows::from([1, 4, 5]));
+        assert_eq!(second, Follows::from([1, 4]));
+        assert_eq!(refused, Follows::from([5]));
+
+        quota.release(&first);
+        let (third, refused) = quota.request(&Follows::new(), Follows::from([5, 6]));
+        assert_eq!(third, Follows::from([5, 6]));
+        assert!(refused.is_empty());
+    }
+}
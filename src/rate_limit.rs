@@ -0,0 +1,116 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Token bucket limiting the messages a websocket client sends.
+// Every message takes a token, tokens come back at a steady rate up to the burst size.
+// Messages sent without a token are violations, which are forgiven once the bucket is full again.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    max_violations: u32,
+    tokens: f64,
+    refilled_at: Instant,
+    violations: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    // Over the rate, the message is to be refused
+    Limited,
+    // Over the rate too many times, the client is to be disconnected
+    Exceeded,
+}
+
+impl RateLimiter {
+    // A rate of 0 disables the limit
+    pub fn new(per_second: u32, burst: u32, max_violations: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+
+        Self {
+            per_second: f64::from(per_second),
+            burst,
+            max_violations,
+            tokens: burst,
+            refilled_at: Instant::now(),
+            violations: 0,
+        }
+    }
+
+    pub fn check(&mut self) -> Verdict {
+        if self.per_second == 0.0 {
+            return Verdict::Allowed;
+        }
+
+        let elapsed = std::mem::replace(&mut self.refilled_at, Instant::now()).elapsed();
+        self.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(self.per_second, self.tokens)
+            .min(self.burst);
+
+        if self.tokens >= self.burst {
+            self.violations = 0;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Verdict::Allowed;
+        }
+
+        self.violations += 1;
+        if self.violations > self.max_violations {
+            Verdict::Exceeded
+        } else {
+            Verdict::Limited
+        }
+    }
+
+    // How long until the next message is allowed
+    pub fn retry_in(&self) -> Duration {
+        if self.per_second == 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.per_second).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut limiter = RateLimiter::new(1, 2, 1);
+
+        assert_eq!(limiter.check(), Verdict::Allowed);
+        assert_eq!(limiter.check(), Verdict::Allowed);
+        assert_eq!(limiter.check(), Verdict::Limited);
+        assert!(limiter.retry_in() > Duration::ZERO);
+        assert_eq!(limiter.check(), Verdict::Exceeded);
+    }
+
+    #[tokio::test]
+    async fn test_refill() {
+        let mut limiter = RateLimiter::new(100, 1, 1);
+
+        assert_eq!(limiter.check(), Verdict::Allowed);
+        assert_eq!(limiter.check(), Verdict::Limited);
+
+        // a full bucket forgives the violations
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(limiter.check(), Verdict::Allowed);
+        assert_eq!(limiter.check(), Verdict::Limited);
+        assert_eq!(limiter.check(), Verdict::Exceeded);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut limiter = RateLimiter::new(0, 1, 0);
+
+        for _ in 0..10 {
+            assert_eq!(limiter.check(), Verdict::Allowed);
+        }
+    }
+}
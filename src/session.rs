@@ -0,0 +1,200 @@
+use crate::{quota::Quota, state::ClientId, twitter::ReceivedTweet, Follows};
+use egg_mode::tweet::Tweet;
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Not,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::sleep,
+};
+
+// Most tweets kept for a disconnected client, the oldest ones are dropped first
+const SESSION_BUFFER_CAPACITY: usize = 256;
+
+// What a websocket client subscribed to, which outlives the connection while it's parked
+#[derive(Debug)]
+pub struct Session {
+    // Who the follows are requested for
+    pub client_id: ClientId,
+    pub follows: Follows,
+    pub rx_tweet: broadcast::Receiver<ReceivedTweet>,
+    // Tweets received while the client was disconnected, oldest first
+    pub missed: VecDeque<ReceivedTweet>,
+}
+
+impl Session {
+    pub fn new(client_id: ClientId, rx_tweet: broadcast::Receiver<ReceivedTweet>) -> Self {
+        Self {
+            client_id,
+            follows: Follows::new(),
+            rx_tweet,
+            missed: VecDeque::new(),
+        }
+    }
+
+    pub fn wants(&self, tweet: &Tweet) -> bool {
+        // send tweets to all clients during debug
+        cfg!(debug_assertions) || self.follows.contains(&tweet.user.as_ref().unwrap().id)
+    }
+}
+
+// Sessions of disconnected clients, kept for the grace period.
+// Each one is held by a task that buffers its tweets until it is resumed or expires,
+// only the way to reach that task is kept in here.
+#[derive(Debug)]
+pub struct Sessions {
+    grace_period: Duration,
+    parked: Mutex<HashMap<String, oneshot::Sender<oneshot::Sender<Session>>>>,
+}
+
+impl Sessions {
+    pub fn new(grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            parked: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.grace_period.is_zero().not()
+    }
+
+    pub const fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    pub fn new_token() -> String {
+        format!("{:032x}", rand::random::<u128>())
+    }
+
+    // Takes a parked session back, None if the token is unknown or the session has expired
+    pub async fn resume(&self, token: &str) -> Option<Session> {
+        let tx_resume = self.parked.lock().unwrap().remove(token)?;
+
+        let (tx_session, rx_session) = oneshot::channel();
+        tx_resume.send(tx_session).ok()?;
+        rx_session.await.ok()
+    }
+
+    // Keeps the session's follows requested and buffers its tweets until it is resumed,
+    // or unsubscribes it once the grace period is over
+    pub fn park(
+        self: &Arc<Self>,
+        token: String,
+        mut session: Session,
+        tx_requested_follows: mpsc::Sender<(ClientId, Follows)>,
+        quota: Arc<Quota>,
+    ) {
+        let (tx_resume, mut rx_resume) = oneshot::channel();
+        self.parked.lock().unwrap().insert(token.clone(), tx_resume);
+
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            let expired = sleep(sessions.grace_period);
+            tokio::pin!(expired);
+
+            loop {
+                tokio::select! {
+                    biased;
+
+                    res = &mut rx_resume => {
+                        if let Ok(tx_session) = res {
+                            let _ = tx_session.send(session);
+                            return;
+                        }
+
+                        break;
+                    }
+
+                    tweet = session.rx_tweet.recv() => match tweet {
+                        Ok(tweet) if session.wants(&tweet.tweet) => {
+                            if session.missed.len() == SESSION_BUFFER_CAPACITY {
+                                session.missed.pop_front();
+                            }
+                            session.missed.push_back(tweet);
+                        }
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        // shutting down
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+
+                    () = &mut expired => {
+                        sessions.parked.lock().unwrap().remove(&token);
+                        break;
+                    }
+                }
+            }
+
+            log::info!(
+                client_id = session.client_id.0;
+                "session of {} expired, unsubscribing", session.client_id
+            );
+
+            quota.release(&session.follows);
+
+            if let Err(error) = tx_requested_follows
+                .send((session.client_id, Follows::new()))
+                .await
+            {
+                log::warn!(
+                    client_id = session.client_id.0,
+                    error = format!("{error:#}");
+                    "failed to unsubscribe {}: {:#}", session.client_id, error
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The tweet sender has to be kept, the session is dropped as if shutting down once it's gone
+    fn parked(
+        grace_period: Duration,
+    ) -> (
+        Arc<Sessions>,
+        mpsc::Receiver<(ClientId, Follows)>,
+        broadcast::Sender<ReceivedTweet>,
+    ) {
+        let sessions = Arc::new(Sessions::new(grace_period));
+        let (tx_requested_follows, rx_requested_follows) = mpsc::channel(1);
+        let (tx_tweet, rx_tweet) = broadcast::channel(1);
+
+        let mut session = Session::new(ClientId(0), rx_tweet);
+        session.follows.insert(1);
+        let quota = Arc::new(Quota::new(10, 10));
+        sessions.park("hunter2".into(), session, tx_requested_follows, quota);
+
+        (sessions, rx_requested_follows, tx_tweet)
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let (sessions, _rx_requested_follows, _tx_tweet) = parked(Duration::from_secs(30));
+
+        assert!(sessions.resume("hunter3").await.is_none());
+
+        let session = sessions.resume("hunter2").await.unwrap();
+        assert_eq!(session.client_id, ClientId(0));
+        assert_eq!(session.follows, Follows::from([1]));
+
+        // tokens can only be used once
+        assert!(sessions.resume("hunter2").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expire() {
+        let (sessions, mut rx_requested_follows, _tx_tweet) = parked(Duration::from_millis(10));
+
+        let (client_id, follows) = rx_requested_follows.recv().await.unwrap();
+        assert_eq!(client_id, ClientId(0));
+        assert!(follows.is_empty());
+
+        assert!(sessions.resume("hunter2").await.is_none());
+    }
+}
@@ -0,0 +1,549 @@
+// Outputs that deliver tweets somewhere else than to websocket and SSE clients
+#![cfg_attr(
+    not(any(feature = "redis", feature = "nats", feature = "mqtt")),
+    allow(dead_code)
+)]
+
+use crate::{
+    api, config,
+    quota::Quota,
+    state::{ClientId, State},
+    twitter::ReceivedTweet,
+    Follows,
+};
+use anyhow::Result;
+use egg_mode::tweet::Tweet;
+use std::{future::Future, ops::Not, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, mpsc};
+use tracing::Instrument;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod webhook;
+
+// Tweets waiting to be delivered while a sink is busy or reconnecting, further ones are dropped
+const QUEUE_CAPACITY: usize = 256;
+
+// An output tweets are delivered to, besides websocket and SSE clients.
+// Every sink runs in its own task, with its own receiver of the tweet broadcast, its own queue
+// and its own metrics, so that a slow or failing sink only holds up itself.
+// A sink subscribes to some follows or to the whole stream, filters the tweets it receives
+// into messages, and delivers them one at a time, in order.
+pub trait OutputSink: Send + Sync + 'static {
+    // Used in metrics, and in logs unless `name` tells more
+    const NAME: &'static str;
+
+    // What a tweet is queued as
+    type Message: Send;
+
+    fn name(&self) -> String {
+        Self::NAME.to_owned()
+    }
+
+    // Follows requested under the sink's own client id, for as long as the service runs.
+    // The sink then only receives the tweets of the granted ones, without any it receives every
+    // tweet of the stream, whoever followed its author.
+    fn subscribe(&self) -> Option<Follows> {
+        None
+    }
+
+    // The message a tweet is delivered as, if it is delivered at all
+    fn filter(&self, tweet: &Tweet) -> Option<Self::Message>;
+
+    // Returns once the message is delivered or given up on
+    fn deliver(
+        &self,
+        message: Self::Message,
+        state: &State,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    // Called with the messages dropped because the queue is full
+    fn overflow(&self, message: Self::Message, state: &State) -> impl Future<Output = ()> + Send {
+        let _ = (message, state);
+        async {}
+    }
+}
+
+// What sink tasks are handed
+#[derive(Clone, Debug)]
+pub struct Context {
+    pub tx_tweet: broadcast::Sender<ReceivedTweet>,
+    pub tx_requested_follows: mpsc::Sender<(ClientId, Follows)>,
+    pub state: Arc<State>,
+    pub quota: Arc<Quota>,
+}
+
+// Starts every webhook and every sink configured under `[sinks]`, each in its own task
+#[cfg_attr(not(feature = "nats"), allow(clippy::unused_async))]
+pub async fn spawn(config: &config::Config, context: &Context) -> Result<()> {
+    let webhooks = config
+        .webhooks
+        .iter()
+        .map(webhook::Webhook::new)
+        .collect::<Result<Vec<_>>>()?;
+    for webhook in webhooks {
+        start(webhook, context);
+    }
+
+    let config = &config.sinks;
+    match &config.redis {
+        #[cfg(feature = "redis")]
+        Some(config) => start(redis::Redis::new(config)?, context),
+        #[cfg(not(feature = "redis"))]
+        Some(_) => {
+            log::warn!("the redis sink is configured, but not built with the `redis` feature");
+        }
+        None => {}
+    }
+
+    match &config.nats {
+        #[cfg(feature = "nats")]
+        Some(config) => start(nats::Nats::new(config, &context.state).await?, context),
+        #[cfg(not(feature = "nats"))]
+        Some(_) => {
+            log::warn!("the nats sink is configured, but not built with the `nats` feature");
+        }
+        None => {}
+    }
+
+    match &config.mqtt {
+        #[cfg(feature = "mqtt")]
+        Some(config) => start(mqtt::Mqtt::new(config, &context.state)?, context),
+        #[cfg(not(feature = "mqtt"))]
+        Some(_) => {
+            log::warn!("the mqtt sink is configured, but not built with the `mqtt` feature");
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+// Subscribes to the tweet broadcast right away, so that none are missed while the task starts
+pub fn start<S: OutputSink>(sink: S, context: &Context) {
+    tokio::spawn(run(
+        sink,
+        context.tx_tweet.subscribe(),
+        context.tx_requested_follows.clone(),
+        context.state.clone(),
+        context.quota.clone(),
+    ));
+}
+
+// Ends when tweets are no longer broadcast, once the service shuts down
+async fn run<S: OutputSink>(
+    sink: S,
+    mut rx_tweet: broadcast::Receiver<ReceivedTweet>,
+    tx_requested_follows: mpsc::Sender<(ClientId, Follows)>,
+    state: Arc<State>,
+    quota: Arc<Quota>,
+) {
+    let name = sink.name();
+    let state = &state;
+
+    let follows = match sink.subscribe() {
+        Some(follows) => {
+            match subscribe(&name, follows, &tx_requested_follows, state, &quota).await {
+                Some(follows) => Some(follows),
+                None => return,
+            }
+        }
+        None => None,
+    };
+
+    let (tx_queue, mut rx_queue) = mpsc::channel(QUEUE_CAPACITY);
+
+    let receiver = async {
+        loop {
+            match rx_tweet.recv().await {
+                Ok(ReceivedTweet { tweet, span }) => {
+                    if let Some(follows) = &follows {
+                        if follows.contains(&tweet.user.as_ref().unwrap().id).not() {
+                            continue;
+                        }
+                    }
+
+                    let Some(message) = sink.filter(&tweet) else {
+                        continue;
+                    };
+
+                    if let Err(mpsc::error::TrySendError::Full((message, _))) =
+                        tx_queue.try_send((message, span))
+                    {
+                        log::warn!(sink = S::NAME; "{} queue is full, dropping a tweet", name);
+                        record_message(S::NAME, "dropped", state);
+                        sink.overflow(message, state).await;
+                    }
+                }
+
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    log::warn!(
+                        sink = S::NAME, count = count;
+                        "{} fell behind, skipped {} tweets", name, count
+                    );
+                    state.metrics.broadcast_lagged.inc();
+                }
+
+                // shutting down
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+
+        // lets the sender finish the queue
+        drop(tx_queue);
+    };
+
+    let sender = async {
+        while let Some((message, span)) = rx_queue.recv().await {
+            let res = sink
+                .deliver(message, state)
+                .instrument(tracing::info_span!(parent: &span, "deliver", sink = S::NAME))
+                .await;
+
+            let result = match res {
+                Ok(()) => "delivered",
+                Err(error) => {
+                    log::error!(
+                        sink = S::NAME, error = format!("{error:#}");
+                        "{} failed to deliver a tweet: {:#}", name, error
+                    );
+                    "failed"
+                }
+            };
+            record_message(S::NAME, result, state);
+        }
+    };
+
+    tokio::join!(receiver, sender);
+}
+
+// Requests the follows of a sink like those of a client, returns the granted ones
+async fn subscribe(
+    name: &str,
+    follows: Follows,
+    tx_requested_follows: &mpsc::Sender<(ClientId, Follows)>,
+    state: &State,
+    quota: &Quota,
+) -> Option<Follows> {
+    let id = state.new_client_id();
+
+    let (follows, refused) = quota.request(&Follows::new(), follows);
+    if refused.is_empty().not() {
+        log::warn!(
+            client_id = id.0, refused = refused.len();
+            "refused {} follows of {} over the follow limits", refused.len(), name
+        );
+        state.metrics.follows_refused.inc_by(refused.len() as u64);
+    }
+
+    if let Err(error) = tx_requested_follows.send((id, follows.clone())).await {
+        log::error!(
+            client_id = id.0, error = format!("{error:#}");
+            "failed to subscribe {}: {:#}", name, error
+        );
+        return None;
+    }
+
+    log::info!(
+        client_id = id.0, follows = follows.len();
+        "{} follows {} users as {}", name, follows.len(), id
+    );
+
+    Some(follows)
+}
+
+fn record_message(sink: &str, result: &str, state: &State) {
+    state
+        .metrics
+        .sink_messages
+        .with_label_values(&[sink, result])
+        .inc();
+}
+
+// What bus sinks deliver: the payload, to every channel, subject or topic it is published to
+#[derive(Debug)]
+pub struct Publication {
+    pub subjects: Vec<String>,
+    pub payload: Vec<u8>,
+}
+
+impl Publication {
+    pub fn new(templates: &[String], tweet: &Tweet) -> Self {
+        let fields = TweetFields::new(tweet);
+
+        Self {
+            subjects: templates
+                .iter()
+                .map(|template| fields.render(template))
+                .collect(),
+            payload: payload(tweet),
+        }
+    }
+}
+
+// The `tweet` message, as sent to websocket clients, is what every output publishes
+pub fn payload(tweet: &Tweet) -> Vec<u8> {
+    // the messages are plain data, serializing them can't fail
+    serde_json::to_vec(&api::ServerMessage::Tweet(api::SerializeWrapper(tweet))).unwrap()
+}
+
+// Templates that render to nothing are not published to
+pub fn non_empty_templates<'a>(templates: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    templates
+        .into_iter()
+        .filter(|template| template.is_empty().not())
+        .cloned()
+        .collect()
+}
+
+// Delay before the given reconnection attempt, starting at `initial` and doubled every time up
+// to `max`
+pub fn reconnect_backoff(initial: Duration, max: Duration, attempt: u32) -> Duration {
+    initial
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(max)
+}
+
+pub fn record_reconnect(sink: &str, state: &State) {
+    state
+        .metrics
+        .sink_reconnects
+        .with_label_values(&[sink])
+        .inc();
+}
+
+// What channel, subject or topic templates can refer to, as `{user_id}`, `{screen_name}` and
+// `{tweet_id}`
+#[derive(Debug)]
+pub struct TweetFields<'a> {
+    pub user_id: u64,
+    pub screen_name: &'a str,
+    pub tweet_id: u64,
+}
+
+impl<'a> TweetFields<'a> {
+    pub fn new(tweet: &'a Tweet) -> Self {
+        let user = tweet.user.as_ref().unwrap();
+
+        Self {
+            user_id: user.id,
+            screen_name: &user.screen_name,
+            tweet_id: tweet.id,
+        }
+    }
+
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{user_id}", &self.user_id.to_string())
+            .replace("{screen_name}", self.screen_name)
+            .replace("{tweet_id}", &self.tweet_id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::UpstreamStatus;
+    use rstest::rstest;
+    use std::sync::Mutex;
+    use tokio::sync::watch;
+
+    // Records the ids of the tweets it delivers, and of those it overflows
+    #[derive(Debug, Default)]
+    struct Recorder {
+        follows: Option<Follows>,
+        delivered: Arc<Mutex<Vec<u64>>>,
+        overflowed: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl OutputSink for Recorder {
+        const NAME: &'static str = "recorder";
+
+        type Message = u64;
+
+        fn subscribe(&self) -> Option<Follows> {
+            self.follows.clone()
+        }
+
+        // skips replies
+        fn filter(&self, tweet: &Tweet) -> Option<Self::Message> {
+            tweet.in_reply_to_status_id.is_none().then_some(tweet.id)
+        }
+
+        async fn deliver(&self, id: Self::Message, _: &State) -> Result<()> {
+            self.delivered.lock().unwrap().push(id);
+            Ok(())
+        }
+
+        async fn overflow(&self, id: Self::Message, _: &State) {
+            self.overflowed.lock().unwrap().push(id);
+        }
+    }
+
+    fn tweet(id: u64, user_id: u64, in_reply_to_status_id: Option<u64>) -> ReceivedTweet {
+        let tweet = serde_json::from_value(serde_json::json!({
+            "created_at": "Sun Oct 02 18:12:04 +0000 2016",
+            "entities": { "hashtags": [], "symbols": [], "urls": [], "user_mentions": [] },
+            "favorite_count": 0,
+            "id": id,
+            "in_reply_to_status_id": in_reply_to_status_id,
+            "retweet_count": 0,
+            "source": "",
+            "text": "tweet",
+            "truncated": false,
+            "user": {
+                "contributors_enabled": false,
+                "created_at": "Sun Oct 02 18:12:04 +0000 2016",
+                "default_profile": true,
+                "default_profile_image": true,
+                "favourites_count": 0,
+                "followers_count": 0,
+                "friends_count": 0,
+                "geo_enabled": false,
+                "id": user_id,
+                "is_translator": false,
+                "listed_count": 0,
+                "name": "user",
+                "profile_background_color": "000000",
+                "profile_image_url": "",
+                "profile_image_url_https": "",
+                "profile_link_color": "000000",
+                "profile_sidebar_border_color": "000000",
+                "profile_sidebar_fill_color": "000000",
+                "profile_text_color": "000000",
+                "profile_use_background_image": false,
+                "protected": false,
+                "screen_name": "user",
+                "statuses_count": 0,
+                "verified": false,
+            },
+        }))
+        .unwrap();
+
+        ReceivedTweet {
+            tweet,
+            span: tracing::Span::none(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let state = Arc::new(State::new(watch::channel(UpstreamStatus::Stopped).1));
+        let (tx_tweet, rx_tweet) = broadcast::channel(16);
+        let (tx_requested_follows, mut rx_requested_follows) = mpsc::channel(1);
+        let sink = Recorder {
+            follows: Some(Follows::from([1, 2, 3])),
+            ..Recorder::default()
+        };
+        let delivered = sink.delivered.clone();
+
+        let task = tokio::spawn(run(
+            sink,
+            rx_tweet,
+            tx_requested_follows,
+            state.clone(),
+            // one of the follows is refused
+            Arc::new(Quota::new(2, 2)),
+        ));
+
+        let (_, follows) = rx_requested_follows.recv().await.unwrap();
+        assert_eq!(follows.len(), 2);
+        let refused = *Follows::from([1, 2, 3])
+            .difference(&follows)
+            .next()
+            .unwrap();
+
+        for user_id in [1, 2, 3, 4] {
+            tx_tweet.send(tweet(user_id * 10, user_id, None)).unwrap();
+        }
+        tx_tweet.send(tweet(11, 1, Some(10))).unwrap();
+        tx_tweet.send(tweet(21, 2, Some(20))).unwrap();
+
+        // the queue is finished once the broadcast ends
+        drop(tx_tweet);
+        task.await.unwrap();
+
+        let mut expected: Vec<u64> = follows.iter().map(|user_id| user_id * 10).collect();
+        expected.sort_unstable();
+        let mut delivered = delivered.lock().unwrap().clone();
+        delivered.sort_unstable();
+        assert_eq!(delivered, expected);
+        assert!(delivered.contains(&(refused * 10)).not());
+        assert_eq!(
+            state
+                .metrics
+                .sink_messages
+                .with_label_values(&[Recorder::NAME, "delivered"])
+                .get(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_overflow() {
+        let state = Arc::new(State::new(watch::channel(UpstreamStatus::Stopped).1));
+        let (tx_tweet, rx_tweet) = broadcast::channel(QUEUE_CAPACITY * 2);
+        let (tx_requested_follows, _) = mpsc::channel(1);
+        let sink = Recorder::default();
+        let (delivered, overflowed) = (sink.delivered.clone(), sink.overflowed.clone());
+
+        // sent before the sink runs, so that its queue fills up
+        let count = QUEUE_CAPACITY as u64 + 10;
+        for id in 0..count {
+            tx_tweet.send(tweet(id, 1, None)).unwrap();
+        }
+        drop(tx_tweet);
+
+        run(
+            sink,
+            rx_tweet,
+            tx_requested_follows,
+            state.clone(),
+            Arc::new(Quota::new(1, 1)),
+        )
+        .await;
+
+        let delivered = delivered.lock().unwrap().len() as u64;
+        let overflowed = overflowed.lock().unwrap().len() as u64;
+        assert!(overflowed > 0);
+        assert_eq!(delivered + overflowed, count);
+        assert_eq!(
+            state
+                .metrics
+                .sink_messages
+                .with_label_values(&[Recorder::NAME, "dropped"])
+                .get(),
+            overflowed
+        );
+    }
+
+    #[rstest]
+    #[case("tweets", "tweets")]
+    #[case("tweets:{user_id}", "tweets:123")]
+    #[case("tweets.{screen_name}.{tweet_id}", "tweets.pajlada.456")]
+    fn test_render(#[case] template: &str, #[case] expected: &str) {
+        let fields = TweetFields {
+            user_id: 123,
+            screen_name: "pajlada",
+            tweet_id: 456,
+        };
+        assert_eq!(fields.render(template), expected);
+    }
+
+    #[rstest]
+    #[case(0, 1)]
+    #[case(1, 2)]
+    #[case(3, 8)]
+    #[case(10, 60)]
+    #[case(100, 60)]
+    fn test_reconnect_backoff(#[case] attempt: u32, #[case] expected: u64) {
+        assert_eq!(
+            reconnect_backoff(Duration::from_secs(1), Duration::from_mins(1), attempt),
+            Duration::from_secs(expected)
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use crate::{config, sinks, state::State};
+use anyhow::{bail, Context, Result};
+use egg_mode::tweet::Tweet;
+use rumqttc::{AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+
+// Publications handed to the event loop, which holds them back while reconnecting
+const CLIENT_CAPACITY: usize = 64;
+
+// Publishes every tweet to MQTT topics.
+// The event loop runs in a task of its own, which reconnects with the configured backoff.
+#[derive(Debug)]
+pub struct Mqtt {
+    client: AsyncClient,
+    topics: Vec<String>,
+    qos: QoS,
+}
+
+impl Mqtt {
+    pub fn new(config: &config::MqttSink, state: &Arc<State>) -> Result<Self> {
+        Self::with_backoff(
+            config,
+            Duration::from_secs(config.reconnect_initial_backoff.get()),
+            Duration::from_secs(config.reconnect_max_backoff.get()),
+            state,
+        )
+    }
+
+    fn with_backoff(
+        config: &config::MqttSink,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+        state: &Arc<State>,
+    ) -> Result<Self> {
+        let qos = match config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            qos => bail!("invalid mqtt qos {}, expected 0, 1 or 2", qos),
+        };
+
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, CLIENT_CAPACITY);
+        tokio::spawn(drive(
+            event_loop,
+            initial_backoff,
+            max_backoff,
+            state.clone(),
+        ));
+
+        Ok(Self {
+            client,
+            topics: sinks::non_empty_templates([&config.topic, &config.firehose_topic]),
+            qos,
+        })
+    }
+}
+
+impl sinks::OutputSink for Mqtt {
+    const NAME: &'static str = "mqtt";
+
+    type Message = sinks::Publication;
+
+    fn filter(&self, tweet: &Tweet) -> Option<Self::Message> {
+        Some(sinks::Publication::new(&self.topics, tweet))
+    }
+
+    async fn deliver(&self, publication: Self::Message, _: &State) -> Result<()> {
+        for topic in &publication.subjects {
+            self.client
+                .publish(topic, self.qos, false, publication.payload.clone())
+                .await
+                .with_context(|| format!("failed to publish to {topic}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Polls the connection, which reconnects on the next poll after an error.
+// Ends once the client is dropped.
+async fn drive(
+    mut event_loop: EventLoop,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    state: Arc<State>,
+) {
+    let mut attempt = 0;
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("connected to mqtt broker");
+                attempt = 0;
+            }
+
+            Ok(_) => {}
+
+            Err(ConnectionError::RequestsDone) => break,
+
+            Err(error) => {
+                let backoff = sinks::reconnect_backoff(initial_backoff, max_backoff, attempt);
+                log::error!(
+                    error = format!("{error:#}");
+                    "mqtt connection failed, reconnecting in {:?}: {:#}", backoff, error
+                );
+                sinks::record_reconnect(<Mqtt as sinks::OutputSink>::NAME, &state);
+                sleep(backoff).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::UpstreamStatus,
+        sinks::{OutputSink, Publication},
+    };
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::watch,
+    };
+
+    type Published = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    // Speaks just enough of MQTT 3.1.1 to record PUBLISH packets, and drops the first
+    // `drop_connections` connections right away
+    async fn stand_in(drop_connections: usize) -> (u16, Published) {
+        let published = Published::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let recorder = published.clone();
+        tokio::spawn(async move {
+            for n in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                if n >= drop_connections {
+                    tokio::spawn(serve(stream, recorder.clone()));
+                }
+            }
+        });
+
+        (port, published)
+    }
+
+    async fn serve(mut stream: TcpStream, published: Published) {
+        while let Some((header, body)) = read_packet(&mut stream).await {
+            match header >> 4 {
+                // CONNECT, accepted
+                1 => stream.write_all(&[0x20, 2, 0, 0]).await.unwrap(),
+
+                // PUBLISH
+                3 => {
+                    let qos = (header >> 1) & 3;
+                    let topic_len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                    let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                    let mut payload = &body[2 + topic_len..];
+                    if qos > 0 {
+                        let packet_id = &payload[..2];
+                        // PUBACK, or PUBREC which the stand-in doesn't follow up on
+                        let ack = if qos == 1 { 0x40 } else { 0x50 };
+                        stream
+                            .write_all(&[ack, 2, packet_id[0], packet_id[1]])
+                            .await
+                            .unwrap();
+                        payload = &payload[2..];
+                    }
+                    published.lock().unwrap().push((topic, payload.to_vec()));
+                }
+
+                // PINGREQ
+                12 => stream.write_all(&[0xd0, 0]).await.unwrap(),
+
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await.ok()?;
+
+        // variable length, 7 bits at a time
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            let byte = stream.read_u8().await.ok()?;
+            len |= usize::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    fn config(port: u16) -> config::MqttSink {
+        config::MqttSink {
+            host: "127.0.0.1".into(),
+            port,
+            client_id: config::MqttSink::default_client_id(),
+            username: None,
+            password: None,
+            topic: config::MqttSink::default_topic(),
+            firehose_topic: String::new(),
+            qos: 1,
+            reconnect_initial_backoff: config::default_reconnect_initial_backoff(),
+            reconnect_max_backoff: config::default_reconnect_max_backoff(),
+        }
+    }
+
+    async fn wait_for(published: &Published, count: usize) {
+        for _ in 0..100 {
+            if published.lock().unwrap().len() >= count {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let (port, published) = stand_in(0).await;
+        let state = Arc::new(State::new(watch::channel(UpstreamStatus::Stopped).1));
+
+        let sink = Mqtt::new(&config(port), &state).unwrap();
+        assert_eq!(sink.topics, ["tweets/{user_id}"]);
+
+        let publication = Publication {
+            subjects: vec!["tweets/1".to_owned(), "tweets".to_owned()],
+            payload: b"{}".to_vec(),
+        };
+        sink.deliver(publication, &state).await.unwrap();
+        wait_for(&published, 2).await;
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            [
+                ("tweets/1".to_owned(), b"{}".to_vec()),
+                ("tweets".to_owned(), b"{}".to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (port, published) = stand_in(2).await;
+        let state = Arc::new(State::new(watch::channel(UpstreamStatus::Stopped).1));
+
+        let backoff = Duration::from_millis(10);
+        let sink = Mqtt::with_backoff(&config(port), backoff, backoff, &state).unwrap();
+        let publication = Publication {
+            subjects: vec!["tweets".to_owned()],
+            payload: b"{}".to_vec(),
+        };
+        sink.deliver(publication, &state).await.unwrap();
+        wait_for(&published, 1).await;
+
+        assert_eq!(published.lock().unwrap().len(), 1);
+        assert_eq!(
+            state
+                .metrics
+                .sink_reconnects
+                .with_label_values(&[Mqtt::NAME])
+                .get(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_invalid_qos() {
+        let state = Arc::new(State::new(watch::channel(UpstreamStatus::Stopped).1));
+        let config = config::MqttSink {
+            qos: 3,
+            ..config(1883)
+        };
+
+        assert!(Mqtt::new(&config, &state).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{config, sinks, state::State};
+use anyhow::{Context, Result};
+use async_nats::{Client, ConnectOptions, Event};
+use egg_mode::tweet::Tweet;
+use std::{sync::Arc, time::Duration};
+
+// Publishes every tweet to NATS subjects.
+// The client reconnects by itself, with the configured backoff, and holds back publications
+// until it is connected again.
+#[derive(Debug)]
+pub struct Nats {
+    client: Client,
+    subjects: Vec<String>,
+}
+
+impl Nats {
+    pub async fn new(config: &config::NatsSink, state: &Arc<State>) -> Result<Self> {
+        let initial_backoff = Duration::from_secs(config.reconnect_initial_backoff.get());
+        let max_backoff = Duration::from_secs(config.reconnect_max_backoff.get());
+        let state = state.clone();
+
+        let client = ConnectOptions::new()
+            .name(env!("CARGO_PKG_NAME"))
+            // don't hold up startup, publications wait for the connection instead
+            .retry_on_initial_connect()
+            .reconnect_delay_callback(move |attempts| {
+                // the first attempt is 1
+                let attempt = u32::try_from(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+                sinks::reconnect_backoff(initial_backoff, max_backoff, attempt)
+            })
+            .event_callback(move |event| {
+                let state = state.clone();
+                async move {
+                    match event {
+                        Event::Connected => log::info!("connected to nats"),
+                        Event::Disconnected => {
+                            log::error!("disconnected from nats, reconnecting");
+                            sinks::record_reconnect(<Self as sinks::OutputSink>::NAME, &state);
+                        }
+                        event => log::warn!(event:% = event; "nats: {}", event),
+                    }
+                }
+            })
+            .connect(config.url.as_str())
+            .await
+            .context("invalid nats url")?;
+
+        Ok(Self {
+            client,
+            subjects: sinks::non_empty_templates([&config.subject, &config.firehose_subject]),
+        })
+    }
+}
+
+impl sinks::OutputSink for Nats {
+    const NAME: &'static str = "nats";
+
+    type Message = sinks::Publication;
+
+    fn filter(&self, tweet: &Tweet) -> Option<Self::Message> {
+        Some(sinks::Publication::new(&self.subjects, tweet))
+    }
+
+    async fn deliver(&self, publication: Self::Message, _: &State) -> Result<()> {
+        for subject in &publication.subjects {
+            self.client
+                .publish(subject.clone(), publication.payload.clone().into())
+                .await
+                .with_context(|| format!("failed to publish to {subject}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::UpstreamStatus,
+        sinks::{OutputSink, Publication},
+    };
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        sync::watch,
+        time::sleep,
+    };
+
+    type Published = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    // Speaks just enough of the NATS protocol to record PUB messages
+    async fn stand_in() -> (String, Published) {
+        let published = Published::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+
+        let recorder = published.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, recorder.clone()));
+            }
+        });
+
+        (url, published)
+    }
+
+    async fn serve(stream: TcpStream, published: Published) {
+        let mut stream = BufReader::new(stream);
+        stream
+            .get_mut()
+            .write_all(b"INFO {\"server_id\":\"stand-in\",\"version\":\"2.10.0\",\"max_payload\":1048576,\"proto\":1}\r\n")
+            .await
+            .unwrap();
+
+        let mut line = String::new();
+        while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("PING") => stream.get_mut().write_all(b"PONG\r\n").await.unwrap(),
+
+                Some("PUB") => {
+                    let subject = words.next().unwrap().to_owned();
+                    let len: usize = words.next().unwrap().parse().unwrap();
+                    let mut payload = vec![0; len + 2];
+                    stream.read_exact(&mut payload).await.unwrap();
+                    payload.truncate(len);
+                    published.lock().unwrap().push((subject, payload));
+                }
+
+                _ => {}
+            }
+            line.clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let (url, published) = stand_in().await;
+        let state = Arc::new(State::new(watch::channel(UpstreamStatus::Stopped).1));
+
+        let config = config::NatsSink {
+            url,
+            subject: config::NatsSink::default_subject(),
+            firehose_subject: String::new(),
+            reconnect_initial_backoff: config::default_reconnect_initial_backoff(),
+            reconnect_max_backoff: config::default_reconnect_max_backoff(),
+        };
+        let sink = Nats::new(&config, &state).await.unwrap();
+        assert_eq!(sink.subjects, ["tweets.{user_id}"]);
+
+        let publication = Publication {
+            subjects: vec!["tweets.1".to_owned(), "tweets".to_owned()],
+            payload: b"{}".to_vec(),
+        };
+        sink.deliver(publication, &state).await.unwrap();
+
+        // published in the background
+        for _ in 0..100 {
+            if published.lock().unwrap().len() == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            [
+                ("tweets.1".to_owned(), b"{}".to_vec()),
+                ("tweets".to_owned(), b"{}".to_vec())
+            ]
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use crate::{config, sinks, state::State};
+use ::redis::{aio::MultiplexedConnection, Client, RedisResult};
+use anyhow::{Context, Result};
+use egg_mode::tweet::Tweet;
+use std::{sync::Mutex, time::Duration};
+use tokio::time::sleep;
+
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Publishes every tweet to redis channels, reconnecting with an exponential backoff whenever
+// the connection is lost. Tweets are kept in order and published once connected again.
+#[derive(Debug)]
+pub struct Redis {
+    client: Client,
+    channels: Vec<String>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    // cloned for every delivery, which are never concurrent
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl Redis {
+    pub fn new(config: &config::RedisSink) -> Result<Self> {
+        Ok(Self {
+            client: Client::open(config.url.as_str()).context("invalid redis url")?,
+            channels: sinks::non_empty_templates([&config.channel, &config.firehose_channel]),
+            initial_backoff: Duration::from_secs(config.reconnect_initial_backoff.get()),
+            max_backoff: Duration::from_secs(config.reconnect_max_backoff.get()),
+            connection: Mutex::default(),
+        })
+    }
+
+    async fn connect(&self) -> RedisResult<MultiplexedConnection> {
+        self.client
+            .get_multiplexed_tokio_connection_with_response_timeouts(
+                RESPONSE_TIMEOUT,
+                CONNECTION_TIMEOUT,
+            )
+            .await
+    }
+}
+
+impl sinks::OutputSink for Redis {
+    const NAME: &'static str = "redis";
+
+    type Message = sinks::Publication;
+
+    fn filter(&self, tweet: &Tweet) -> Option<Self::Message> {
+        Some(sinks::Publication::new(&self.channels, tweet))
+    }
+
+    // Retries until the tweet is published
+    async fn deliver(&self, publication: Self::Message, state: &State) -> Result<()> {
+        let mut attempt = 0;
+
+        loop {
+            let connection = self.connection.lock().unwrap().clone();
+            let mut connection = match connection {
+                Some(connection) => connection,
+                None => match self.connect().await {
+                    Ok(connection) => {
+                        *self.connection.lock().unwrap() = Some(connection.clone());
+                        connection
+                    }
+                    Err(error) => {
+                        let backoff = sinks::reconnect_backoff(
+                            self.initial_backoff,
+                            self.max_backoff,
+                            attempt,
+                        );
+                        log::error!(
+                            error = format!("{error:#}");
+                            "failed to connect to redis, retrying in {:?}: {:#}", backoff, error
+                        );
+                        sinks::record_reconnect(Self::NAME, state);
+                        sleep(backoff).await;
+                        attempt += 1;
+                        continue;
+                    }
+                },
+            };
+
+            let mut pipe = ::redis::pipe();
+            for channel in &publication.subjects {
+                pipe.cmd("PUBLISH")
+                    .arg(channel)
+                    .arg(&publication.payload)
+                    .ignore();
+            }
+
+            match pipe.query_async::<_, ()>(&mut connection).await {
+                Ok(()) => return Ok(()),
+
+                Err(error) => {
+                    log::error!(
+                        error = format!("{error:#}");
+                        "failed to publish to redis, reconnecting: {:#}", error
+                    );
+                    sinks::record_reconnect(Self::NAME, state);
+                    *self.connection.lock().unwrap() = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::UpstreamStatus,
+        sinks::{OutputSink, Publication},
+    };
+    use futures::StreamExt;
+    use std::{process::Stdio, sync::Arc};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        process::{Child, Command},
+        sync::watch,
+        time::timeout,
+    };
+
+    type Published = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    // A redis-server of our own, without persistence, killed once dropped
+    async fn redis_server(port: u16) -> Child {
+        let server = Command::new("redis-server")
+            .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+            .args(["--save", "", "--appendonly", "no"])
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("redis-server isn't installed");
+
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return server;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("redis-server didn't start listening on port {port}");
+    }
+
+    // Subscribes to `channels` on the server at `url`
+    async fn subscribe(url: &str, channels: &[&str]) -> ::redis::aio::PubSub {
+        let mut pubsub = Client::open(url).unwrap().get_async_pubsub().await.unwrap();
+        pubsub.subscribe(channels).await.unwrap();
+        pubsub
+    }
+
+    // Waits for the next `count` messages of the subscription
+    async fn received(pubsub: &mut ::redis::aio::PubSub, count: usize) -> Vec<(String, Vec<u8>)> {
+        let messages = pubsub.on_message().take(count).map(|message| {
+            let channel = message.get_channel_name().to_owned();
+            (channel, message.get_payload_bytes().to_vec())
+        });
+        timeout(Duration::from_secs(5), messages.collect())
+            .await
+            .unwrap()
+    }
+
+    // Speaks just enough of the redis protocol to record PUBLISH commands, and drops the first
+    // `drop_connections` connections right away, which a real server can't be made to do
+    async fn stand_in(drop_connections: usize) -> (String, Published) {
+        let published = Published::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+
+        let recorder = published.clone();
+        tokio::spawn(async move {
+            for n in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                if n >= drop_connections {
+                    tokio::spawn(serve(stream, recorder.clone()));
+                }
+            }
+        });
+
+        (url, published)
+    }
+
+    async fn serve(stream: TcpStream, published: Published) {
+        let mut stream = BufReader::new(stream);
+
+        while let Some(command) = read_command(&mut stream).await {
+            let reply: &[u8] = if command[0].eq_ignore_ascii_case(b"PUBLISH") {
+                let channel = String::from_utf8(command[1].clone()).unwrap();
+                published
+                    .lock()
+                    .unwrap()
+                    .push((channel, command[2].clone()));
+                b":1\r\n"
+            } else {
+                b"+OK\r\n"
+            };
+            stream.get_mut().write_all(reply).await.unwrap();
+        }
+    }
+
+    // An array of bulk strings
+    async fn read_command(stream: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+        async fn read_len(stream: &mut BufReader<TcpStream>, prefix: u8) -> Option<usize> {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.ok()?;
+            let line = line.trim_end().strip_prefix(prefix as char)?;
+            line.parse().ok()
+        }
+
+        let len = read_len(stream, b'*').await?;
+        let mut command = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut arg = vec![0; read_len(stream, b'$').await? + 2];
+            stream.read_exact(&mut arg).await.ok()?;
+            arg.truncate(arg.len() - 2);
+            command.push(arg);
+        }
+        Some(command)
+    }
+
+    fn sink(url: String) -> Redis {
+        let mut sink = Redis::new(&config::RedisSink {
+            url,
+            channel: config::RedisSink::default_channel(),
+            firehose_channel: config::RedisSink::default_firehose_channel(),
+            reconnect_initial_backoff: config::default_reconnect_initial_backoff(),
+            reconnect_max_backoff: config::default_reconnect_max_backoff(),
+        })
+        .unwrap();
+        sink.initial_backoff = Duration::from_millis(10);
+        sink.max_backoff = Duration::from_millis(10);
+        sink
+    }
+
+    fn state() -> State {
+        State::new(watch::channel(UpstreamStatus::Stopped).1)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server, run with --ignored"]
+    async fn test_publish() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("redis://127.0.0.1:{port}/");
+        let mut server = redis_server(port).await;
+        let state = state();
+        let sink = sink(url.clone());
+        assert_eq!(sink.channels, ["tweets:{user_id}", "tweets"]);
+
+        let mut pubsub = subscribe(&url, &["tweets:1", "tweets"]).await;
+        let publication = Publication {
+            subjects: vec!["tweets:1".to_owned(), "tweets".to_owned()],
+            payload: b"{}".to_vec(),
+        };
+        sink.deliver(publication, &state).await.unwrap();
+        assert_eq!(
+            received(&mut pubsub, 2).await,
+            [
+                ("tweets:1".to_owned(), b"{}".to_vec()),
+                ("tweets".to_owned(), b"{}".to_vec())
+            ]
+        );
+
+        // the connection is lost with the server, and opened again once it's back
+        server.kill().await.unwrap();
+        let _server = redis_server(port).await;
+        let mut pubsub = subscribe(&url, &["tweets"]).await;
+        let publication = Publication {
+            subjects: vec!["tweets".to_owned()],
+            payload: b"{}".to_vec(),
+        };
+        sink.deliver(publication, &state).await.unwrap();
+        assert_eq!(
+            received(&mut pubsub, 1).await,
+            [("tweets".to_owned(), b"{}".to_vec())]
+        );
+        assert!(
+            state
+                .metrics
+                .sink_reconnects
+                .with_label_values(&[Redis::NAME])
+                .get()
+                >= 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (url, published) = stand_in(2).await;
+        let state = state();
+        let sink = sink(url);
+
+        let publication = Publication {
+            subjects: vec!["tweets".to_owned()],
+            payload: b"{}".to_vec(),
+        };
+        sink.deliver(publication, &state).await.unwrap();
+
+        assert_eq!(published.lock().unwrap().len(), 1);
+        assert_eq!(
+            state
+                .metrics
+                .sink_reconnects
+                .with_label_values(&[Redis::NAME])
+                .get(),
+            2
+        );
+    }
+}
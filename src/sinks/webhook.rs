@@ -0,0 +1,300 @@
+use crate::{config, sinks, state::State, Follows};
+use anyhow::{anyhow, Context, Result};
+use egg_mode::tweet::Tweet;
+use hmac::{Hmac, Mac};
+use reqwest::{header, StatusCode, Url};
+use sha2::Sha256;
+use std::{path::PathBuf, time::Duration};
+use tokio::{io::AsyncWriteExt, time::sleep};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_mins(1);
+
+pub const SIGNATURE_HEADER: &str = "x-tweet-provider-signature";
+
+// Posts the tweets of a static follow list to a URL, without any websocket client.
+// Tweets given up on, after retries or because the queue is full, go to the dead letter file.
+#[derive(Debug)]
+pub struct Webhook {
+    url: Url,
+    follows: Follows,
+    secret: Option<String>,
+    max_retries: u32,
+    dead_letter_file: Option<PathBuf>,
+    initial_backoff: Duration,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(config: &config::Webhook) -> Result<Self> {
+        Ok(Self {
+            url: config
+                .url
+                .parse()
+                .with_context(|| format!("invalid webhook url {:?}", config.url))?,
+            follows: config.follows.iter().copied().collect(),
+            secret: config.secret.clone(),
+            max_retries: config.max_retries,
+            dead_letter_file: config.dead_letter_file.clone(),
+            initial_backoff: INITIAL_BACKOFF,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .user_agent(concat!("tweet-provider/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+        })
+    }
+
+    // Posts a tweet, retrying with an exponential backoff on network errors and statuses that
+    // may go away, such as 5xx and 429
+    async fn post_with_retries(&self, body: &[u8], state: &State) -> Result<()> {
+        let mut backoff = self.initial_backoff;
+        let mut error = String::new();
+
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                log::warn!(
+                    webhook:% = self.url, attempt = attempt, error = error;
+                    "delivery to webhook {} failed, retrying in {:?}: {}", self.url, backoff, error
+                );
+                state.metrics.webhook_retries.inc();
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+
+            match self.post(body).await {
+                Ok(status) if status.is_success() => return Ok(()),
+
+                Ok(status) if retryable(status) => error = format!("status {status}"),
+
+                Ok(status) => {
+                    error = format!("status {status}");
+                    break;
+                }
+
+                Err(e) => error = format!("{e:#}"),
+            }
+        }
+
+        self.dead_letter(body, &error).await;
+        Err(anyhow!(error))
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<StatusCode, reqwest::Error> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, body));
+        }
+
+        Ok(request.send().await?.status())
+    }
+
+    // Appends a delivery that was given up on to the dead letter file, if any
+    async fn dead_letter(&self, body: &[u8], error: &str) {
+        let Some(path) = &self.dead_letter_file else {
+            return;
+        };
+
+        let mut line = serde_json::to_vec(&serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339(),
+            "url": self.url.as_str(),
+            "error": error,
+            // the body is always our own JSON
+            "body": serde_json::from_slice::<serde_json::Value>(body).unwrap(),
+        }))
+        .unwrap();
+        line.push(b'\n');
+
+        let res = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&line).await?;
+            // tokio files write in the background, until flushed
+            file.flush().await
+        };
+
+        if let Err(error) = res.await {
+            log::error!(
+                webhook:% = self.url, path:? = path, error = format!("{error:#}");
+                "failed to write to dead letter file {:?}: {:#}", path, error
+            );
+        }
+    }
+}
+
+impl sinks::OutputSink for Webhook {
+    const NAME: &'static str = "webhook";
+
+    type Message = Vec<u8>;
+
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    fn subscribe(&self) -> Option<Follows> {
+        Some(self.follows.clone())
+    }
+
+    fn filter(&self, tweet: &Tweet) -> Option<Self::Message> {
+        Some(sinks::payload(tweet))
+    }
+
+    async fn deliver(&self, body: Self::Message, state: &State) -> Result<()> {
+        self.post_with_retries(&body, state).await
+    }
+
+    async fn overflow(&self, body: Self::Message, _: &State) {
+        self.dead_letter(&body, "queue full").await;
+    }
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+// `sha256=` followed by the hex HMAC-SHA256 of the body
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::UpstreamStatus;
+    use axum::{extract::State as Extract, http::HeaderMap, routing::post, Router};
+    use std::sync::{Arc, Mutex};
+    use tokio::{net::TcpListener, sync::watch};
+
+    type Received = Arc<Mutex<Vec<(Option<String>, Vec<u8>)>>>;
+
+    // Answers with the given statuses in order, the last one repeating, and records the
+    // signature and body of each request
+    async fn stand_in(statuses: Vec<StatusCode>) -> (Url, Received) {
+        let received = Received::default();
+
+        let router = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |Extract((statuses, received)): Extract<(Arc<Vec<StatusCode>>, Received)>,
+                     headers: HeaderMap,
+                     body: axum::body::Bytes| async move {
+                        let signature = headers
+                            .get(SIGNATURE_HEADER)
+                            .map(|value| value.to_str().unwrap().to_owned());
+                        let mut received = received.lock().unwrap();
+                        received.push((signature, body.to_vec()));
+                        statuses[(received.len() - 1).min(statuses.len() - 1)]
+                    },
+                ),
+            )
+            .with_state((Arc::new(statuses), received.clone()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        (url.parse().unwrap(), received)
+    }
+
+    fn webhook(url: &Url, dead_letter_file: Option<PathBuf>) -> Webhook {
+        let config = config::Webhook {
+            url: url.to_string(),
+            follows: vec![1],
+            secret: Some("secret".into()),
+            max_retries: 2,
+            dead_letter_file,
+        };
+        let mut webhook = Webhook::new(&config).unwrap();
+        webhook.initial_backoff = Duration::from_millis(10);
+        webhook
+    }
+
+    fn state() -> State {
+        State::new(watch::channel(UpstreamStatus::Stopped).1)
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("secret", b"{}"),
+            "sha256=77325902caca812dc259733aacd046b73817372c777b8d95b402647474516e13"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries() {
+        let (url, received) = stand_in(vec![
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::NO_CONTENT,
+        ])
+        .await;
+        let state = state();
+
+        assert!(webhook(&url, None)
+            .post_with_retries(b"{}", &state)
+            .await
+            .is_ok());
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 3);
+        for (signature, body) in &received {
+            assert_eq!(signature.as_deref(), Some(sign("secret", b"{}").as_str()));
+            assert_eq!(body, b"{}");
+        }
+        assert_eq!(state.metrics.webhook_retries.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_dead_letter() {
+        let (url, received) = stand_in(vec![StatusCode::BAD_REQUEST]).await;
+        let state = state();
+        let path = std::env::temp_dir().join(format!(
+            "tweet-provider-dead-letter-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        // client errors are not retried
+        assert!(webhook(&url, Some(path.clone()))
+            .post_with_retries(br#"{"a":1}"#, &state)
+            .await
+            .is_err());
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let line: serde_json::Value = serde_json::from_str(lines.trim_end()).unwrap();
+        assert_eq!(line["url"], url.as_str());
+        assert_eq!(line["error"], "status 400 Bad Request");
+        assert_eq!(line["body"], serde_json::json!({ "a": 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_gives_up() {
+        let (url, received) = stand_in(vec![StatusCode::BAD_GATEWAY]).await;
+        let state = state();
+
+        assert!(webhook(&url, None)
+            .post_with_retries(b"{}", &state)
+            .await
+            .is_err());
+
+        // the first attempt and two retries
+        assert_eq!(received.lock().unwrap().len(), 3);
+        assert_eq!(state.metrics.webhook_retries.get(), 2);
+    }
+}
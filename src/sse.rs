@@ -0,0 +1,241 @@
+use crate::{
+    api,
+    auth::{self, Auth},
+    net::{query_param, PeerAddr},
+    quota::Quota,
+    session::Session,
+    state::{ClientId, State},
+    twitter::ReceivedTweet,
+    websocket::client_name_from_request,
+    Follows,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State as Extract},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::Stream;
+use std::{convert::Infallible, net::SocketAddr, ops::Not, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, mpsc, watch};
+
+// What `GET /events` needs to register clients like the websocket listener does
+#[derive(Debug)]
+pub struct Events {
+    pub tx_requested_follows: mpsc::Sender<(ClientId, Follows)>,
+    pub tx_tweet: broadcast::Sender<ReceivedTweet>,
+    pub rx_upstream_status: watch::Receiver<api::UpstreamStatus>,
+    pub auth: Arc<Auth>,
+    pub quota: Arc<Quota>,
+    // How often a comment is sent to keep idle connections open
+    pub keep_alive: Duration,
+}
+
+// Streams the same messages as the websocket to a client that follows the users in
+// `?follow=1,2,3`, for the lifetime of the request
+pub async fn events(
+    Extract((events, state)): Extract<(Arc<Events>, Arc<State>)>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+) -> Response {
+    if events.auth.is_enabled() {
+        let role = auth::token_from_request(&request).and_then(|token| events.auth.role(&token));
+        if role.is_none() {
+            return (StatusCode::UNAUTHORIZED, "authentication failed").into_response();
+        }
+    }
+
+    let follows = match parse_follows(&query_param(&request, "follow").unwrap_or_default()) {
+        Ok(follows) => follows,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, format!("invalid follow: {error}")).into_response()
+        }
+    };
+
+    let id = state.new_client_id();
+    let addr = PeerAddr::Tcp(addr);
+    state.client_connected(id, addr);
+
+    let name = client_name_from_request(&request);
+    if let Some(name) = &name {
+        state.client_named(id, name);
+    }
+
+    log::info!(
+        client_id = id.0, addr:% = addr, client_name:? = name;
+        "new event stream for {} from {}", id, addr
+    );
+
+    let (granted, refused) = events.quota.request(&Follows::new(), follows);
+    if refused.is_empty().not() {
+        log::warn!(
+            client_id = id.0, addr:% = addr, refused = refused.len();
+            "refused {} follows from {} over the follow limits", refused.len(), id
+        );
+        state.metrics.follows_refused.inc_by(refused.len() as u64);
+    }
+
+    if let Err(error) = events
+        .tx_requested_follows
+        .send((id, granted.clone()))
+        .await
+    {
+        log::error!(
+            client_id = id.0, error = format!("{error:#}");
+            "failed to subscribe {}: {:#}", id, error
+        );
+        state.client_disconnected(id);
+        events.quota.release(&granted);
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    state.client_follows_changed(id, granted.len());
+
+    let mut session = Session::new(id, events.tx_tweet.subscribe());
+    session.follows = granted;
+
+    let registration = Registration {
+        id,
+        events: events.clone(),
+        state: state.clone(),
+        follows: session.follows.clone(),
+    };
+
+    let keep_alive = KeepAlive::new()
+        .interval(events.keep_alive)
+        .text("keep-alive");
+
+    Sse::new(stream(
+        session,
+        refused,
+        events.rx_upstream_status.clone(),
+        registration,
+    ))
+    .keep_alive(keep_alive)
+    .into_response()
+}
+
+fn stream(
+    mut session: Session,
+    refused: Follows,
+    mut rx_upstream_status: watch::Receiver<api::UpstreamStatus>,
+    registration: Registration,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    async_stream::stream! {
+        // dropped along with the stream, once the client is gone
+        let registration = registration;
+        let state = &registration.state;
+
+        let upstream_status = rx_upstream_status.borrow_and_update().clone();
+        yield Ok(event(&api::ServerMessage::UpstreamStatus(&upstream_status)));
+
+        yield Ok(event(&api::Reply {
+            message: api::ServerMessage::AckSubscriptions(&session.follows),
+            id: None,
+            refused: refused.is_empty().not().then_some(&refused),
+        }));
+
+        let mut upstream_open = true;
+
+        loop {
+            tokio::select! {
+                tweet = session.rx_tweet.recv() => match tweet {
+                    Ok(ReceivedTweet { tweet, span }) => {
+                        if session.wants(&tweet).not() {
+                            continue;
+                        }
+
+                        // not entered, it would have to be held across the yield
+                        let _span = tracing::info_span!(parent: &span, "deliver_tweet");
+                        state
+                            .metrics
+                            .tweets_delivered
+                            .with_label_values(&[&registration.id.0.to_string()])
+                            .inc();
+                        yield Ok(event(&api::ServerMessage::Tweet(api::SerializeWrapper(&tweet))));
+                    }
+
+                    // there is no queue to drop from, but the client still gets to know
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        state.metrics.broadcast_lagged.inc();
+                        yield Ok(event(&api::ServerMessage::Dropped { count }));
+                    }
+
+                    // shutting down
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+
+                res = rx_upstream_status.changed(), if upstream_open => {
+                    if res.is_err() {
+                        upstream_open = false;
+                        continue;
+                    }
+
+                    let upstream_status = rx_upstream_status.borrow_and_update().clone();
+                    yield Ok(event(&api::ServerMessage::UpstreamStatus(&upstream_status)));
+                }
+            }
+        }
+    }
+}
+
+fn event<T: serde::Serialize>(message: &T) -> Event {
+    // the messages are plain data, serializing them can't fail
+    Event::default().data(serde_json::to_string(message).unwrap())
+}
+
+// `1,2,3`, nothing means no follows
+fn parse_follows(follow: &str) -> Result<Follows, std::num::ParseIntError> {
+    follow
+        .split(',')
+        .map(str::trim)
+        .filter(|id| id.is_empty().not())
+        .map(str::parse)
+        .collect()
+}
+
+// Unsubscribes the client once its stream is dropped
+struct Registration {
+    id: ClientId,
+    events: Arc<Events>,
+    state: Arc<State>,
+    follows: Follows,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        log::info!(client_id = self.id.0; "event stream for {} closed", self.id);
+
+        self.state.client_disconnected(self.id);
+        self.events.quota.release(&self.follows);
+
+        let id = self.id;
+        let tx_requested_follows = self.events.tx_requested_follows.clone();
+        tokio::spawn(async move {
+            if let Err(error) = tx_requested_follows.send((id, Follows::new())).await {
+                log::warn!(
+                    client_id = id.0, error = format!("{error:#}");
+                    "failed to unsubscribe {}: {:#}", id, error
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("", Some(&[][..]))]
+    #[case("1,2,3", Some(&[1, 2, 3][..]))]
+    #[case("1, 2,", Some(&[1, 2][..]))]
+    #[case("1,two", None)]
+    #[case("-1", None)]
+    fn test_parse_follows(#[case] follow: &str, #[case] expected: Option<&[u64]>) {
+        let expected = expected.map(|ids| ids.iter().copied().collect::<Follows>());
+        assert_eq!(parse_follows(follow).ok(), expected);
+    }
+}
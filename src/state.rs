@@ -0,0 +1,288 @@
+use crate::{api::UpstreamStatus, metrics::Metrics, net::PeerAddr, twitter::RequestedFollows};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::watch;
+
+// Read-only view of the service, shared between the websocket listener, the twitter supervisor
+// and the HTTP status listener.
+// Apart from the metrics, everything in here is a copy of what the tasks already keep for
+// themselves, so the locks are only held long enough to swap or clone the data.
+#[derive(Debug)]
+pub struct State {
+    started_at: Instant,
+    rx_upstream_status: watch::Receiver<UpstreamStatus>,
+    requested_follows: Mutex<RequestedFollows>,
+    clients: Mutex<HashMap<ClientId, Client>>,
+    next_client_id: AtomicU64,
+    backoff: AtomicU32,
+    // Last time the twitter stream was connected or nothing was requested
+    upstream_ready_at: Mutex<Instant>,
+    websocket_listening: AtomicBool,
+    last_tweet_at: Mutex<Option<SystemTime>>,
+    last_upstream_message_at: Mutex<Option<Instant>>,
+    pub metrics: Metrics,
+}
+
+// Unique to each websocket connection, unlike addresses which can be reused or shared by proxies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+pub struct ClientId(pub u64);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub addr: PeerAddr,
+    // Given by the client during the handshake
+    pub name: Option<String>,
+    pub connected_at: SystemTime,
+    pub follows: usize,
+    // Round-trip time of the latest answered ping
+    pub rtt: Option<Duration>,
+}
+
+impl State {
+    pub fn new(rx_upstream_status: watch::Receiver<UpstreamStatus>) -> Self {
+        Self {
+            started_at: Instant::now(),
+            rx_upstream_status,
+            requested_follows: Mutex::default(),
+            clients: Mutex::default(),
+            next_client_id: AtomicU64::new(0),
+            backoff: AtomicU32::new(0),
+            upstream_ready_at: Mutex::new(Instant::now()),
+            websocket_listening: AtomicBool::new(false),
+            last_tweet_at: Mutex::default(),
+            last_upstream_message_at: Mutex::default(),
+            metrics: Metrics::new(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn upstream_status(&self) -> UpstreamStatus {
+        self.rx_upstream_status.borrow().clone()
+    }
+
+    // Called by the supervisor when publishing a new status
+    pub fn upstream_status_changed(&self, previous: &UpstreamStatus, status: &UpstreamStatus) {
+        // it was connected up until now
+        if *previous == UpstreamStatus::Connected && *status != UpstreamStatus::Connected {
+            self.upstream_ready();
+        }
+    }
+
+    // How long the twitter stream has been down for while follows were requested,
+    // zero if it is up or nothing is requested
+    pub fn upstream_down_for(&self) -> Duration {
+        if *self.rx_upstream_status.borrow() == UpstreamStatus::Connected
+            || self.requested_follows.lock().unwrap().is_empty()
+        {
+            return Duration::ZERO;
+        }
+
+        self.upstream_ready_at.lock().unwrap().elapsed()
+    }
+
+    fn upstream_ready(&self) {
+        *self.upstream_ready_at.lock().unwrap() = Instant::now();
+    }
+
+    pub fn websocket_listening(&self) -> bool {
+        self.websocket_listening.load(Ordering::Relaxed)
+    }
+
+    pub fn set_websocket_listening(&self, listening: bool) {
+        self.websocket_listening.store(listening, Ordering::Relaxed);
+    }
+
+    pub fn requested_follows(&self) -> RequestedFollows {
+        self.requested_follows.lock().unwrap().clone()
+    }
+
+    pub fn set_requested_follows(&self, requested_follows: &RequestedFollows) {
+        let mut current = self.requested_follows.lock().unwrap();
+
+        // nothing was requested up until now
+        if current.is_empty() || requested_follows.is_empty() {
+            self.upstream_ready();
+        }
+
+        requested_follows.clone_into(&mut current);
+        drop(current);
+
+        self.metrics
+            .follows
+            .set(requested_follows.len().try_into().unwrap_or(i64::MAX));
+    }
+
+    pub fn clients(&self) -> HashMap<ClientId, Client> {
+        self.clients.lock().unwrap().clone()
+    }
+
+    // Shared by websocket and event stream clients
+    pub fn new_client_id(&self) -> ClientId {
+        ClientId(self.next_client_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn client_connected(&self, id: ClientId, addr: PeerAddr) {
+        self.clients.lock().unwrap().insert(
+            id,
+            Client {
+                addr,
+                name: None,
+                connected_at: SystemTime::now(),
+                follows: 0,
+                rtt: None,
+            },
+        );
+        self.metrics.connected_clients.inc();
+    }
+
+    pub fn client_named(&self, id: ClientId, name: &str) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.name = Some(name.into());
+        }
+    }
+
+    pub fn client_follows_changed(&self, id: ClientId, follows: usize) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.follows = follows;
+        }
+    }
+
+    pub fn client_rtt_measured(&self, id: ClientId, rtt: Duration) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.rtt = Some(rtt);
+        }
+    }
+
+    pub fn client_disconnected(&self, id: ClientId) {
+        self.clients.lock().unwrap().remove(&id);
+        self.metrics.connected_clients.dec();
+        // client ids are never reused, don't let them pile up
+        let _ = self
+            .metrics
+            .tweets_delivered
+            .remove_label_values(&[&id.0.to_string()]);
+    }
+
+    pub fn backoff(&self) -> u32 {
+        self.backoff.load(Ordering::Relaxed)
+    }
+
+    pub fn set_backoff(&self, backoff: u32) {
+        self.backoff.store(backoff, Ordering::Relaxed);
+        self.metrics.backoff.set(backoff.into());
+    }
+
+    pub fn last_tweet_at(&self) -> Option<SystemTime> {
+        *self.last_tweet_at.lock().unwrap()
+    }
+
+    pub fn tweet_received(&self) {
+        *self.last_tweet_at.lock().unwrap() = Some(SystemTime::now());
+        self.metrics.tweets_received.inc();
+    }
+
+    // Time since the twitter stream last sent anything, or since startup
+    pub fn last_upstream_message_age(&self) -> Duration {
+        self.last_upstream_message_at
+            .lock()
+            .unwrap()
+            .unwrap_or(self.started_at)
+            .elapsed()
+    }
+
+    pub fn upstream_message_received(&self) {
+        *self.last_upstream_message_at.lock().unwrap() = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+    use std::{ops::Not, thread};
+
+    fn state() -> State {
+        State::new(watch::channel(UpstreamStatus::Stopped).1)
+    }
+
+    #[test]
+    fn test_clients() {
+        let state = state();
+        let (first, second) = (state.new_client_id(), state.new_client_id());
+        assert_ne!(first, second);
+
+        state.client_connected(first, PeerAddr::Unix);
+        state.client_connected(second, PeerAddr::Unix);
+        state.client_named(first, "pajbot");
+        state.client_follows_changed(first, 3);
+        state.client_rtt_measured(first, Duration::from_millis(20));
+        assert_eq!(state.metrics.connected_clients.get(), 2);
+
+        let client = &state.clients()[&first];
+        assert_eq!(client.name.as_deref(), Some("pajbot"));
+        assert_eq!(client.follows, 3);
+        assert_eq!(client.rtt, Some(Duration::from_millis(20)));
+
+        state.client_disconnected(first);
+        assert_eq!(state.clients().keys().collect::<Vec<_>>(), [&second]);
+        assert_eq!(state.metrics.connected_clients.get(), 1);
+
+        // updates of clients that are gone are ignored
+        state.client_follows_changed(first, 5);
+        assert!(state.clients().contains_key(&first).not());
+    }
+
+    #[rstest]
+    #[case(UpstreamStatus::Stopped, false, false)]
+    #[case(UpstreamStatus::Connected, true, false)]
+    #[case(UpstreamStatus::Stopped, true, true)]
+    #[case(UpstreamStatus::Connecting, true, true)]
+    fn test_upstream_down_for(
+        #[case] status: UpstreamStatus,
+        #[case] requested: bool,
+        #[case] down: bool,
+    ) {
+        let (_tx_upstream_status, rx_upstream_status) = watch::channel(status);
+        let state = State::new(rx_upstream_status);
+        if requested {
+            state.set_requested_follows(&RequestedFollows::from([(1, [ClientId(0)].into())]));
+        }
+        thread::sleep(Duration::from_millis(10));
+
+        let down_for = state.upstream_down_for();
+        if down {
+            assert!(down_for >= Duration::from_millis(10), "{down_for:?}");
+        } else {
+            assert_eq!(down_for, Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn test_upstream_down_for_disconnect() {
+        let (tx_upstream_status, rx_upstream_status) = watch::channel(UpstreamStatus::Connected);
+        let state = State::new(rx_upstream_status);
+        state.set_requested_follows(&RequestedFollows::from([(1, [ClientId(0)].into())]));
+        thread::sleep(Duration::from_millis(50));
+
+        // the downtime counts from the disconnect, not from when follows were requested
+        tx_upstream_status.send_replace(UpstreamStatus::Connecting);
+        state.upstream_status_changed(&UpstreamStatus::Connected, &UpstreamStatus::Connecting);
+        assert!(state.upstream_down_for() < Duration::from_millis(50));
+    }
+}
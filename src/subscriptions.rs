@@ -0,0 +1,182 @@
+use crate::{
+    config,
+    quota::Quota,
+    state::{ClientId, State},
+    Follows,
+};
+use egg_mode::{self as twitter, user::TwitterUser};
+use std::{collections::HashSet, ops::Not, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time::sleep};
+
+// Most screen names twitter looks up at once
+const LOOKUP_BATCH_SIZE: usize = 100;
+const LOOKUP_INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const LOOKUP_MAX_BACKOFF: Duration = Duration::from_mins(5);
+
+// Twitter's "No user matches for specified terms", when none of the screen names exist
+const NO_USER_MATCHES: i32 = 17;
+
+// Requests the follows of the `[[subscriptions]]` tables under their own client id, so that the
+// twitter stream runs whether or not any client is connected.
+// User ids are requested right away, handles are added once they are looked up.
+pub async fn subscribe(
+    subscriptions: Vec<config::Subscription>,
+    token: twitter::Token,
+    id: ClientId,
+    tx_requested_follows: mpsc::Sender<(ClientId, Follows)>,
+    state: Arc<State>,
+    quota: Arc<Quota>,
+) {
+    let mut follows: Follows = subscriptions
+        .iter()
+        .flat_map(|subscription| subscription.follows.iter().copied())
+        .collect();
+    let handles: HashSet<String> = subscriptions
+        .iter()
+        .flat_map(|subscription| subscription.handles.iter())
+        .map(|handle| normalize_handle(handle))
+        .collect();
+
+    let mut granted = Follows::new();
+
+    if follows.is_empty().not() {
+        granted = request(
+            id,
+            &granted,
+            follows.clone(),
+            &tx_requested_follows,
+            &state,
+            &quota,
+        )
+        .await;
+    }
+
+    if handles.is_empty() {
+        return;
+    }
+
+    let users = lookup(&handles, &token).await;
+    let screen_names = users.iter().map(|user| user.screen_name.as_str());
+    for handle in missing_handles(&handles, screen_names) {
+        log::warn!(handle = handle; "static subscription to unknown user @{}", handle);
+    }
+
+    follows.extend(users.iter().map(|user| user.id));
+    request(id, &granted, follows, &tx_requested_follows, &state, &quota).await;
+}
+
+async fn request(
+    id: ClientId,
+    old: &Follows,
+    new: Follows,
+    tx_requested_follows: &mpsc::Sender<(ClientId, Follows)>,
+    state: &State,
+    quota: &Quota,
+) -> Follows {
+    let (granted, refused) = quota.request(old, new);
+    if refused.is_empty().not() {
+        log::warn!(
+            client_id = id.0, refused = refused.len();
+            "refused {} static subscriptions over the follow limits", refused.len()
+        );
+        state.metrics.follows_refused.inc_by(refused.len() as u64);
+    }
+
+    log::info!(
+        client_id = id.0, follows = granted.len();
+        "static subscriptions follow {} users as {}", granted.len(), id
+    );
+
+    if let Err(error) = tx_requested_follows.send((id, granted.clone())).await {
+        log::error!(
+            client_id = id.0, error = format!("{error:#}");
+            "failed to request static subscriptions: {:#}", error
+        );
+    }
+
+    granted
+}
+
+// Retries until twitter answers, with an exponential backoff
+async fn lookup(handles: &HashSet<String>, token: &twitter::Token) -> Vec<TwitterUser> {
+    let handles: Vec<String> = handles.iter().cloned().collect();
+    let mut users = Vec::new();
+
+    for batch in handles.chunks(LOOKUP_BATCH_SIZE) {
+        let mut backoff = LOOKUP_INITIAL_BACKOFF;
+
+        loop {
+            match twitter::user::lookup(batch.to_vec(), token).await {
+                Ok(response) => {
+                    users.extend(response.response);
+                    break;
+                }
+
+                Err(twitter::error::Error::TwitterError(_, errors))
+                    if errors
+                        .errors
+                        .iter()
+                        .any(|error| error.code == NO_USER_MATCHES) =>
+                {
+                    break;
+                }
+
+                Err(error) => {
+                    log::error!(
+                        error = format!("{error:#}");
+                        "failed to look up static subscriptions, retrying in {:?}: {:#}",
+                        backoff, error
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(LOOKUP_MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    users
+}
+
+// Screen names are case insensitive, and often written with an @
+fn normalize_handle(handle: &str) -> String {
+    handle.trim().trim_start_matches('@').to_lowercase()
+}
+
+fn missing_handles<'a, 'b>(
+    handles: &'a HashSet<String>,
+    screen_names: impl Iterator<Item = &'b str>,
+) -> Vec<&'a str> {
+    let found: HashSet<String> = screen_names.map(normalize_handle).collect();
+
+    let mut missing: Vec<&str> = handles
+        .iter()
+        .filter(|handle| found.contains(*handle).not())
+        .map(String::as_str)
+        .collect();
+    missing.sort_unstable();
+    missing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("pajlada", "pajlada")]
+    #[case("@Pajlada", "pajlada")]
+    #[case(" @forsen ", "forsen")]
+    fn test_normalize_handle(#[case] handle: &str, #[case] expected: &str) {
+        assert_eq!(normalize_handle(handle), expected);
+    }
+
+    #[test]
+    fn test_missing_handles() {
+        let handles = HashSet::from(["pajlada".into(), "forsen".into(), "nobody".into()]);
+
+        assert_eq!(
+            missing_handles(&handles, ["Pajlada", "forsen"].into_iter()),
+            ["nobody"]
+        );
+    }
+}
@@ -0,0 +1,35 @@
+// Exports tracing spans over OTLP, only built with the `otlp` feature.
+// The exporter is configured through the standard OTEL_EXPORTER_OTLP_* environment variables.
+
+use anyhow::Result;
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::layer::SubscriberExt;
+
+pub fn init() -> Result<()> {
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").into());
+
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(
+            trace::Config::default()
+                .with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+        )
+        .install_batch(runtime::Tokio)?;
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider);
+
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)),
+    )?;
+
+    Ok(())
+}
+
+// Flushes the spans that haven't been exported yet, needs the runtime to still be running
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -1,6 +1,11 @@
 #![allow(clippy::unnecessary_mut_passed)] // futures::select!
 
-use crate::{config, Follows};
+use crate::{
+    api::UpstreamStatus,
+    config,
+    state::{ClientId, State},
+    Follows,
+};
 use anyhow::{Context, Result};
 use egg_mode::{self as twitter, tweet::Tweet};
 use futures::{
@@ -9,16 +14,24 @@ use futures::{
 };
 use std::{
     collections::{HashMap, HashSet},
-    net::SocketAddr,
     ops::Not,
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch},
     time::{sleep, timeout},
 };
+use tracing::Instrument;
+
+pub type RequestedFollows = HashMap<u64, HashSet<ClientId>>;
 
-type RequestedFollows = HashMap<u64, HashSet<SocketAddr>>;
+// A tweet along with the span it was received in, so that delivering it can be traced back
+#[derive(Clone, Debug)]
+pub struct ReceivedTweet {
+    pub tweet: Tweet,
+    pub span: tracing::Span,
+}
 
 const NEW_FOLLOWS_RESTART_DELAY: Duration = Duration::from_secs(10);
 const TWITTER_STALL: Duration = Duration::from_secs(90);
@@ -32,6 +45,15 @@ enum ErrorKind {
 }
 
 impl ErrorKind {
+    const fn label(self) -> &'static str {
+        match self {
+            Self::RateLimited => "rate_limited",
+            Self::BadStatus => "bad_status",
+            Self::NetError => "net_error",
+            Self::Unspecific => "unspecific",
+        }
+    }
+
     fn from_error(error: anyhow::Error) -> Self {
         // TODO: shouldn't need to downcast
         match error.downcast() {
@@ -54,10 +76,13 @@ impl ErrorKind {
 // starts the twitter stream
 // restarts it when it goes down
 // restarts it when there are new users to follow
+#[allow(clippy::too_many_lines)]
 pub async fn supervisor(
     config: config::Twitter,
-    mut rx_requested_follows: mpsc::Receiver<(SocketAddr, Follows)>,
-    tx_tweet: broadcast::Sender<Tweet>,
+    mut rx_requested_follows: mpsc::Receiver<(ClientId, Follows)>,
+    tx_tweet: broadcast::Sender<ReceivedTweet>,
+    tx_upstream_status: watch::Sender<UpstreamStatus>,
+    state: Arc<State>,
 ) -> Result<()> {
     // The follows requested and their subscribers
     let mut requested_follows = RequestedFollows::new();
@@ -90,6 +115,7 @@ pub async fn supervisor(
             // and start a new stream
             () = restart => {
                 backing_off = false;
+                state.metrics.backoff_seconds.set(0.0);
 
                 if requested_follows.is_empty() {
                     if twitter_stream.is_terminated().not() {
@@ -98,25 +124,61 @@ pub async fn supervisor(
                     }
 
                     log::info!("no follows were requested, let's wait some more");
+                    publish_status(&tx_upstream_status, &state, UpstreamStatus::Stopped);
                     continue;
                 }
 
                 let follows: Follows = requested_follows.keys().copied().collect();
-                twitter_stream
-                    .set(stream_consumer(config.token(), follows, tx_tweet.clone()).fuse());
+                publish_status(&tx_upstream_status, &state, UpstreamStatus::Connecting);
+
+                let span = tracing::info_span!("stream_session", follow_count = follows.len());
+                twitter_stream.set(
+                    stream_consumer(
+                        config.token(),
+                        follows,
+                        tx_tweet.clone(),
+                        tx_upstream_status.clone(),
+                        state.clone(),
+                    )
+                    .instrument(span)
+                    .fuse(),
+                );
             }
 
             // The stream has ended, we inspect the given error to know how much we should be
             // delaying the restart, and schedule said restart
             res = twitter_stream => {
                 let error = res.err().context("infinite loop cannot return Ok(())")?;
-                log::error!("twitter stream error: {:#}", error);
-
+                let reason = format!("{error:#}");
                 let error_kind = ErrorKind::from_error(error);
+                log::error!(
+                    error = reason, error_kind = error_kind.label();
+                    "twitter stream error: {}", reason
+                );
                 let delay = inspect_error(error_kind, &mut backoff);
                 backing_off = true;
-
-                log::info!("restarting in {:?}", delay);
+                state.set_backoff(backoff);
+                state
+                    .metrics
+                    .stream_restarts
+                    .with_label_values(&[error_kind.label()])
+                    .inc();
+                state.metrics.backoff_seconds.set(delay.as_secs_f64());
+
+                log::info!(
+                    error_kind = error_kind.label(),
+                    restart_delay_secs = delay.as_secs_f64(),
+                    backoff = backoff;
+                    "restarting in {:?}", delay
+                );
+                publish_status(
+                    &tx_upstream_status,
+                    &state,
+                    UpstreamStatus::BackingOff {
+                        retry_in: delay,
+                        reason,
+                    },
+                );
                 restart.set(sleep(delay).fuse());
             }
 
@@ -124,15 +186,23 @@ pub async fn supervisor(
             // If a normal (not backing off) restart was already scheduled, we ignore it and
             // re-schedule to 10 seconds.
             msg = rx_requested_follows.next() => {
-                let (addr, new_follows) = msg.context("no tx_requested_follows remaining")?;
+                let (client_id, new_follows) = msg.context("no tx_requested_follows remaining")?;
+
+                let span = tracing::info_span!(
+                    "follows_update",
+                    client_id = client_id.0,
+                    follow_count = new_follows.len(),
+                    requires_restart = tracing::field::Empty,
+                );
+                let _entered = span.enter();
 
-                // We remove addr from subscriptions it doesn't want anymore
+                // We remove the client from subscriptions it doesn't want anymore
                 for (follow, subscribers) in &mut requested_follows {
                     if new_follows.contains(follow) {
                         continue;
                     }
 
-                    subscribers.retain(|s| s != &addr);
+                    subscribers.retain(|s| s != &client_id);
                 }
 
                 let num_follows_before = requested_follows.len();
@@ -147,15 +217,22 @@ pub async fn supervisor(
 
                     requested_follows
                         .entry(follow)
-                        .or_insert_with(HashSet::new)
-                        .insert(addr);
+                        .or_default()
+                        .insert(client_id);
                 }
 
                 requires_restart = requires_restart
                     || (twitter_stream.is_terminated().not() && requested_follows.is_empty());
 
+                state.set_requested_follows(&requested_follows);
+                span.record("requires_restart", requires_restart);
+
                 if requires_restart && backing_off.not() {
-                    log::info!("found new follows, restarting in {:?}", NEW_FOLLOWS_RESTART_DELAY);
+                    log::info!(
+                        follow_count = requested_follows.len(),
+                        restart_delay_secs = NEW_FOLLOWS_RESTART_DELAY.as_secs_f64();
+                        "found new follows, restarting in {:?}", NEW_FOLLOWS_RESTART_DELAY
+                    );
                     if restart.is_terminated().not() {
                         log::info!("intercepted an existing scheduled restart");
                     }
@@ -170,6 +247,24 @@ pub async fn supervisor(
     }
 }
 
+// Publishes the new status to websocket clients, unless it is unchanged
+fn publish_status(
+    tx_upstream_status: &watch::Sender<UpstreamStatus>,
+    state: &State,
+    status: UpstreamStatus,
+) {
+    tx_upstream_status.send_if_modified(|current| {
+        if *current == status {
+            return false;
+        }
+
+        log::info!(upstream_status:serde = status; "upstream status: {:?}", status);
+        state.upstream_status_changed(current, &status);
+        *current = status;
+        true
+    });
+}
+
 fn inspect_error(error_kind: ErrorKind, backoff: &mut u32) -> Duration {
     match error_kind {
         ErrorKind::RateLimited => {
@@ -211,7 +306,9 @@ fn inspect_error(error_kind: ErrorKind, backoff: &mut u32) -> Duration {
 async fn stream_consumer(
     token: twitter::Token,
     follows: HashSet<u64>,
-    tx_tweet: broadcast::Sender<Tweet>,
+    tx_tweet: broadcast::Sender<ReceivedTweet>,
+    tx_upstream_status: watch::Sender<UpstreamStatus>,
+    state: Arc<State>,
 ) -> Result<()> {
     use twitter::stream::StreamMessage;
 
@@ -223,7 +320,10 @@ async fn stream_consumer(
         follows.len()
     );
 
-    log::info!("starting a new twitter stream with follows: {:?}", follows);
+    log::info!(
+        follow_count = follows.len();
+        "starting a new twitter stream with follows: {:?}", follows
+    );
 
     let mut stream = twitter::stream::filter()
         .follow(&follows.iter().copied().collect::<Vec<_>>())
@@ -237,6 +337,10 @@ async fn stream_consumer(
         // TODO: read up on these errors
         let msg = msg?; // twitter error
 
+        // the first message we get means the connection went through
+        publish_status(&tx_upstream_status, &state, UpstreamStatus::Connected);
+        state.upstream_message_received();
+
         match msg {
             StreamMessage::Tweet(tweet) => {
                 let user = tweet.user.as_ref().unwrap();
@@ -245,9 +349,16 @@ async fn stream_consumer(
                     continue;
                 }
 
-                log::info!("got a tweet from {}: {:?}", user.name, tweet.text);
+                log::info!(
+                    user_id = user.id, tweet_id = tweet.id;
+                    "got a tweet from {}: {:?}", user.name, tweet.text
+                );
+                state.tweet_received();
+
+                // child of the stream session, parent of every delivery
+                let span = tracing::info_span!("tweet", tweet_id = tweet.id, user_id = user.id);
 
-                if tx_tweet.send(tweet).is_err() {
+                if tx_tweet.send(ReceivedTweet { tweet, span }).is_err() {
                     log::debug!("no rx_tweet available");
                 }
             }
@@ -275,15 +386,15 @@ mod test {
     use rstest::rstest;
 
     #[rstest]
-    #[case(0, Duration::from_secs(60), 1)]
-    #[case(1, Duration::from_secs(120), 2)]
-    #[case(2, Duration::from_secs(240), 3)]
-    #[case(3, Duration::from_secs(480), 4)]
-    #[case(4, Duration::from_secs(960), 5)]
-    #[case(5, Duration::from_secs(960), 6)]
-    #[case(100, Duration::from_secs(960), 101)]
-    #[case(u32::MAX-1, Duration::from_secs(960), u32::MAX)]
-    #[case(u32::MAX, Duration::from_secs(960), u32::MAX)]
+    #[case(0, Duration::from_mins(1), 1)]
+    #[case(1, Duration::from_mins(2), 2)]
+    #[case(2, Duration::from_mins(4), 3)]
+    #[case(3, Duration::from_mins(8), 4)]
+    #[case(4, Duration::from_mins(16), 5)]
+    #[case(5, Duration::from_mins(16), 6)]
+    #[case(100, Duration::from_mins(16), 101)]
+    #[case(u32::MAX-1, Duration::from_mins(16), u32::MAX)]
+    #[case(u32::MAX, Duration::from_mins(16), u32::MAX)]
     fn test_420(
         #[case] mut initial_backoff: u32,
         #[case] expected_duration: Duration,
@@ -325,7 +436,7 @@ mod test {
     #[case(1, Duration::from_millis(250), 2)]
     #[case(2, Duration::from_millis(500), 3)]
     #[case(3, Duration::from_millis(750), 4)]
-    #[case(4, Duration::from_millis(1_000), 5)]
+    #[case(4, Duration::from_secs(1), 5)]
     #[case(5, Duration::from_millis(1_250), 6)]
     #[case(100, Duration::from_secs(16), 101)]
     #[case(u32::MAX-1, Duration::from_secs(16), u32::MAX)]
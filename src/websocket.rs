@@ -1,123 +1,520 @@
-use crate::{api, Follows};
+use crate::{
+    api, auth, config,
+    deflate::{Deflate, DeflateStream},
+    encoding::Encoding,
+    heartbeat::Heartbeat,
+    net::{query_param, Listener, PeerAddr, Stream},
+    queue::{Pushed, Queue},
+    quota::Quota,
+    rate_limit::{RateLimiter, Verdict},
+    session::{Session, Sessions},
+    state::{ClientId, State},
+    tls::Tls,
+    twitter::ReceivedTweet,
+    Follows,
+};
 use anyhow::{Context, Result};
 use async_tungstenite::{
     self as ws,
-    tungstenite::{error::Error as WsError, protocol::WebSocketConfig, Message},
+    tungstenite::{
+        error::Error as WsError,
+        handshake::server::{Request, Response},
+        http,
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Message,
+    },
 };
-use egg_mode::tweet::Tweet;
 use futures::{sink::Sink, FutureExt, SinkExt, StreamExt};
-use std::{net::SocketAddr, ops::Not, sync::Arc, time::Duration};
+use std::{fmt, ops::Not, sync::Arc, time::Duration};
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{broadcast, mpsc, Notify},
+    sync::{broadcast, mpsc, watch, Notify},
     time::{interval_at, timeout, Instant},
 };
+use tracing::Instrument;
+
+// How long a client has to complete the tls and websocket handshakes
+const WS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a client has to send an auth message if it didn't give a token during the handshake
+const WS_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Longest name a client can give itself during the handshake
+const CLIENT_NAME_MAX_LEN: usize = 64;
 
-const WS_HEARTBEAT: Duration = Duration::from_secs(30);
-const WS_SEND_QUEUE_CAPACITY: usize = 32;
-const WS_STALL: Duration = Duration::from_secs(90);
+// A websocket client, as identified in logs
+#[derive(Clone, Debug)]
+struct Peer {
+    id: ClientId,
+    addr: PeerAddr,
+    // Given by the client during the handshake
+    name: Option<String>,
+    // Known once authenticated
+    role: Option<auth::Role>,
+    // Negotiated during the handshake
+    encoding: Encoding,
+}
 
+// #3 pajbot-forsen (127.0.0.1:50302)
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(name) = &self.name {
+            write!(f, " {name}")?;
+        }
+        write!(f, " ({})", self.addr)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn listener(
-    listener: TcpListener,
-    tx_requested_follows: mpsc::Sender<(SocketAddr, Follows)>,
-    tx_tweet: broadcast::Sender<Tweet>,
+    listener: Listener,
+    config: Arc<config::WebSocket>,
+    tx_requested_follows: mpsc::Sender<(ClientId, Follows)>,
+    tx_tweet: broadcast::Sender<ReceivedTweet>,
+    rx_upstream_status: watch::Receiver<api::UpstreamStatus>,
+    state: &Arc<State>,
+    auth: &Arc<auth::Auth>,
+    sessions: &Arc<Sessions>,
+    quota: &Arc<Quota>,
+    tls: Option<&Arc<Tls>>,
     lifeline: &Arc<Notify>,
 ) -> Result<()> {
-    log::info!("listening on {}", listener.local_addr().unwrap());
+    log::info!(
+        "listening on {}{}",
+        listener.local_addr(),
+        if tls.is_some() { " with tls" } else { "" }
+    );
+    state.set_websocket_listening(true);
+    // the loop only ends by being dropped, when the service shuts down
+    let _listening = Listening(state);
 
     loop {
         match listener.accept().await {
             Ok((stream, addr)) => {
-                log::info!("new connection from {}", addr);
+                let mut peer = Peer {
+                    id: state.new_client_id(),
+                    addr,
+                    name: None,
+                    role: None,
+                    encoding: Encoding::Json,
+                };
+
+                log::info!(
+                    client_id = peer.id.0, addr:% = peer.addr;
+                    "new connection from {}", peer
+                );
 
-                let mut tx_requested_follows = tx_requested_follows.clone();
+                let tx_requested_follows = tx_requested_follows.clone();
                 let rx_tweet = tx_tweet.subscribe();
+                let rx_upstream_status = rx_upstream_status.clone();
+                let state = state.clone();
+                let config = config.clone();
+                let auth = auth.clone();
+                let sessions = sessions.clone();
+                let quota = quota.clone();
+                let tls = tls.cloned();
 
                 let lifeline_clone = lifeline.clone();
                 tokio::spawn(async move {
+                    state.client_connected(peer.id, peer.addr);
+
+                    let span = tracing::info_span!(
+                        "websocket_connection",
+                        client_id = peer.id.0,
+                        addr = %peer.addr,
+                        client_name = tracing::field::Empty,
+                    );
+
+                    let mut session = Session::new(peer.id, rx_tweet);
+                    let session_token = sessions.is_enabled().then(Sessions::new_token);
+
                     let res = handler(
                         stream,
-                        addr,
-                        &mut tx_requested_follows,
-                        rx_tweet,
+                        &mut peer,
+                        &mut session,
+                        session_token.as_deref(),
+                        &tx_requested_follows,
+                        rx_upstream_status,
+                        &config,
+                        &state,
+                        &auth,
+                        &sessions,
+                        &quota,
+                        tls.as_deref(),
                         lifeline_clone,
                     )
+                    .instrument(span)
                     .await;
 
-                    if let Err(error) = res {
-                        if let Some(WsError::ConnectionClosed) = error.downcast_ref() {
-                            return;
-                        }
-
-                        log::error!("error processing websocket for {}: {:#}", addr, error);
-                    }
-
-                    if let Err(error) = tx_requested_follows.send((addr, Follows::new())).await {
-                        log::warn!("failed to unsubscribe {}: {:#}", addr, error);
-                    }
+                    disconnected(
+                        res,
+                        &peer,
+                        session,
+                        session_token,
+                        tx_requested_follows,
+                        &state,
+                        &sessions,
+                        quota,
+                    )
+                    .await;
                 });
             }
 
-            Err(error) => log::error!("failed new connection: {:#}", error),
+            Err(error) => log::error!(
+                error = format!("{error:#}");
+                "failed new connection: {:#}", error
+            ),
         }
     }
 }
 
+// Parks the session of a client that went away if it may resume it, or unsubscribes it.
+// Whether the connection ended with an error or not, its follows have to be let go of.
+#[allow(clippy::too_many_arguments)]
+async fn disconnected(
+    res: Result<()>,
+    peer: &Peer,
+    session: Session,
+    session_token: Option<String>,
+    tx_requested_follows: mpsc::Sender<(ClientId, Follows)>,
+    state: &State,
+    sessions: &Arc<Sessions>,
+    quota: Arc<Quota>,
+) {
+    state.client_disconnected(peer.id);
+
+    if let Err(error) = res {
+        // the client went away without a close frame, nothing to report
+        if matches!(error.downcast_ref(), Some(WsError::ConnectionClosed)).not() {
+            log::error!(
+                client_id = peer.id.0,
+                addr:% = peer.addr,
+                error = format!("{error:#}");
+                "error processing websocket for {}: {:#}", peer, error
+            );
+        }
+    }
+
+    // only authenticated clients were given their token
+    if let (Some(token), Some(_)) = (session_token, peer.role) {
+        log::info!(
+            client_id = peer.id.0, addr:% = peer.addr;
+            "keeping the session of {} for {:?}", peer, sessions.grace_period()
+        );
+        sessions.park(token, session, tx_requested_follows, quota);
+        return;
+    }
+
+    quota.release(&session.follows);
+
+    if let Err(error) = tx_requested_follows.send((peer.id, Follows::new())).await {
+        log::warn!(
+            client_id = peer.id.0,
+            addr:% = peer.addr,
+            error = format!("{error:#}");
+            "failed to unsubscribe {}: {:#}", peer, error
+        );
+    }
+}
+
+// Clears `websocket_listening` once the listener is gone, so that /healthz fails
+struct Listening<'a>(&'a State);
+
+impl Drop for Listening<'_> {
+    fn drop(&mut self) {
+        self.0.set_websocket_listening(false);
+    }
+}
+
+// result_large_err: the handshake callback's signature is imposed by tungstenite
+#[allow(
+    clippy::too_many_lines,
+    clippy::too_many_arguments,
+    clippy::result_large_err
+)]
 async fn handler(
-    stream: TcpStream,
-    addr: SocketAddr,
-    tx_requested_follows: &mut mpsc::Sender<(SocketAddr, Follows)>,
-    mut rx_tweet: broadcast::Receiver<Tweet>,
+    stream: Box<dyn Stream>,
+    peer: &mut Peer,
+    session: &mut Session,
+    session_token: Option<&str>,
+    tx_requested_follows: &mpsc::Sender<(ClientId, Follows)>,
+    rx_upstream_status: watch::Receiver<api::UpstreamStatus>,
+    config: &config::WebSocket,
+    state: &State,
+    auth: &auth::Auth,
+    sessions: &Sessions,
+    quota: &Quota,
+    tls: Option<&Tls>,
     lifeline: Arc<Notify>,
 ) -> Result<()> {
-    let mut follows = Follows::new();
+    let mut handshake_token = None;
+    let mut client_name = None;
+    let mut resume_token = None;
+    let mut encoding = Encoding::Json;
+    let mut deflate = None;
+    let handshake = async {
+        let stream: Box<dyn Stream> = match tls {
+            Some(tls) => Box::new(tls.accept(stream).await.context("tls handshake failed")?),
+            None => Box::new(stream),
+        };
 
-    let stream = ws::tokio::TokioAdapter::new(stream);
-    let ws_config = WebSocketConfig {
-        max_send_queue: Some(WS_SEND_QUEUE_CAPACITY),
-        ..WebSocketConfig::default()
+        let stream = ws::tokio::TokioAdapter::new(DeflateStream::new(stream));
+        let ws = ws::accept_hdr_async_with_config(
+            stream,
+            |request: &Request, mut response: Response| {
+                handshake_token = auth::token_from_request(request);
+                client_name = client_name_from_request(request);
+                resume_token = resume_token_from_request(request);
+                encoding = Encoding::negotiate(request, &mut response);
+                deflate = Deflate::negotiate(config, request, &mut response);
+                Ok(response)
+            },
+            Some(WebSocketConfig::default()),
+        )
+        .await?;
+        anyhow::Ok(ws)
     };
-
-    let ws = ws::accept_async_with_config(stream, Some(ws_config)).await?;
+    let mut ws = timeout(WS_HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .context("ws connection did not complete the handshake in time")??;
+    if let Some(deflate) = deflate {
+        ws.get_mut().get_mut().enable(deflate);
+    }
     let (mut tx_ws, rx_ws) = ws.split();
+    peer.encoding = encoding;
+
+    if let Some(name) = client_name {
+        log::info!(
+            client_id = peer.id.0, addr:% = peer.addr, client_name = name;
+            "{} is named {}", peer, name
+        );
+        tracing::Span::current().record("client_name", &name);
+        state.client_named(peer.id, &name);
+        peer.name = Some(name);
+    }
 
     let mut rx_ws = rx_ws.fuse();
-    let mut heartbeat = interval_at(Instant::now(), WS_HEARTBEAT);
+
+    peer.role = if auth.is_enabled() {
+        authenticate(handshake_token, &mut rx_ws, auth, peer.encoding).await?
+    } else {
+        Some(auth::Auth::anonymous_role())
+    };
+
+    if peer.role.is_none() {
+        log::warn!(
+            client_id = peer.id.0, addr:% = peer.addr;
+            "{} failed to authenticate", peer
+        );
+
+        tx_ws
+            .send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "authentication failed".into(),
+            })))
+            .await?;
+
+        return Ok(());
+    }
+
+    // tweets go through the queue, everything else is written right away
+    let (tx_control, rx_control) = futures::channel::mpsc::unbounded();
+    let queue = Queue::new(config.queue_capacity, config.slow_client_policy);
+
+    tokio::try_join!(
+        conversation(
+            peer,
+            session,
+            session_token,
+            resume_token,
+            tx_control,
+            &queue,
+            rx_ws,
+            tx_requested_follows,
+            rx_upstream_status,
+            state,
+            auth,
+            sessions,
+            quota,
+            config,
+            &lifeline,
+        ),
+        writer(tx_ws, rx_control, &queue, peer, state),
+    )?;
+
+    Ok(())
+}
+
+// Everything that happens once the client is authenticated, apart from writing tweets
+#[allow(clippy::too_many_lines, clippy::too_many_arguments)]
+async fn conversation<R>(
+    peer: &Peer,
+    session: &mut Session,
+    session_token: Option<&str>,
+    resume_token: Option<String>,
+    mut tx_ws: futures::channel::mpsc::UnboundedSender<Message>,
+    queue: &Queue<ReceivedTweet>,
+    mut rx_ws: R,
+    tx_requested_follows: &mpsc::Sender<(ClientId, Follows)>,
+    mut rx_upstream_status: watch::Receiver<api::UpstreamStatus>,
+    state: &State,
+    auth: &auth::Auth,
+    sessions: &Sessions,
+    quota: &Quota,
+    config: &config::WebSocket,
+    lifeline: &Arc<Notify>,
+) -> Result<()>
+where
+    R: futures::stream::FusedStream<Item = Result<Message, WsError>> + Unpin,
+{
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    let pong_timeout = Duration::from_secs(config.pong_timeout);
+    let mut ping_interval = interval_at(
+        Instant::now(),
+        Duration::from_secs(config.heartbeat_interval.max(1)),
+    );
+    let mut heartbeat = Heartbeat::default();
+    let mut rate_limiter = RateLimiter::new(
+        config.rate_limit_per_second,
+        config.rate_limit_burst,
+        config.rate_limit_max_violations,
+    );
+
+    // let the client know right away whether tweets can be expected
+    let mut upstream_open = true;
+    let upstream_status = rx_upstream_status.borrow_and_update().clone();
+    send_message(
+        &mut tx_ws,
+        peer.encoding,
+        &api::ServerMessage::UpstreamStatus(&upstream_status),
+    )
+    .await?;
+
+    if let Some(token) = session_token {
+        let resumed = match resume_token {
+            Some(resume_token) => sessions.resume(&resume_token).await,
+            None => None,
+        };
+
+        send_message(
+            &mut tx_ws,
+            peer.encoding,
+            &api::ServerMessage::Session(api::Session {
+                token,
+                resumed: resumed.is_some(),
+                grace_period: sessions.grace_period(),
+            }),
+        )
+        .await?;
+
+        if let Some(resumed) = resumed {
+            resume(
+                peer,
+                session,
+                resumed,
+                &mut tx_ws,
+                queue,
+                tx_requested_follows,
+                state,
+            )
+            .await?;
+        }
+    }
 
     loop {
         tokio::select! {
-            ws_msg = timeout(WS_STALL, rx_ws.next()).fuse() => {
+            ws_msg = timeout(idle_timeout, rx_ws.next()).fuse() => {
                 let ws_msg = ws_msg.context("ws connection stalled")?;
                 let ws_msg = ws_msg.context("ws stream ended")?;
                 let ws_msg = ws_msg?; // websocket closed or error
 
+                // only messages with data make the server do any work
+                if let Some((encoding, data)) = frame_data(&ws_msg, peer.encoding) {
+                    match rate_limiter.check() {
+                        Verdict::Allowed => {}
+
+                        Verdict::Limited => {
+                            log::debug!(
+                                client_id = peer.id.0, addr:% = peer.addr;
+                                "ignoring message from {} over the rate limit", peer
+                            );
+                            state.metrics.rate_limited.inc();
+
+                            let message = format!(
+                                "sending too many messages, retry in {:.1}s",
+                                rate_limiter.retry_in().as_secs_f64()
+                            );
+                            let error_message = api::ProtocolError {
+                                code: api::ErrorCode::RateLimited,
+                                message: &message,
+                            };
+                            send_reply(
+                                &mut tx_ws,
+                                peer.encoding,
+                                api::ServerMessage::ProtocolError(error_message),
+                                api::ClientRequest::peek_id(encoding, data).as_ref(),
+                            )
+                            .await?;
+
+                            continue;
+                        }
+
+                        Verdict::Exceeded => {
+                            log::warn!(
+                                client_id = peer.id.0, addr:% = peer.addr;
+                                "disconnecting {} for repeatedly going over the rate limit", peer
+                            );
+                            state.metrics.rate_limit_disconnects.inc();
+
+                            tx_ws
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: CloseCode::Policy,
+                                    reason: "sending too many messages".into(),
+                                })))
+                                .await?;
+
+                            break;
+                        }
+                    }
+                }
+
                 handle_ws_message(
                     ws_msg,
-                    addr,
-                    &mut follows,
+                    peer,
+                    &mut session.follows,
+                    &mut heartbeat,
                     &mut tx_ws,
                     tx_requested_follows,
-                    &lifeline,
+                    state,
+                    auth,
+                    quota,
+                    lifeline,
                 )
                 .await?;
             }
 
-            tweet = rx_tweet.recv() => {
+            tweet = session.rx_tweet.recv() => {
                 let tweet = match tweet {
                     Ok(tweet) => tweet,
                     Err(broadcast::error::RecvError::Lagged(n)) => {
-                        log::warn!("lagging {} items behind", n);
+                        log::warn!(
+                            client_id = peer.id.0, addr:% = peer.addr, lagged = n;
+                            "{} lagging {} items behind", peer, n
+                        );
+                        state.metrics.broadcast_lagged.inc();
+                        queue.count_dropped(n);
                         continue;
                     }
                     Err(broadcast::error::RecvError::Closed) => {
                         // rx_tweet ran out, hopefully we're shutting down
 
-                        use async_tungstenite::tungstenite::protocol;
-
-                        log::info!("closing {}", addr);
+                        log::info!(
+                            client_id = peer.id.0, addr:% = peer.addr;
+                            "closing {}", peer
+                        );
                         tx_ws
-                            .send(Message::Close(Some(protocol::CloseFrame {
-                                code: protocol::frame::coding::CloseCode::Error,
+                            .send(Message::Close(Some(CloseFrame {
+                                code: CloseCode::Error,
                                 reason: "tweet-provider was interrupted or encountered an error".into(),
                             })))
                         .await?;
@@ -126,36 +523,245 @@ async fn handler(
                     }
                 };
 
-                log::debug!("sending tweet to {}", addr);
+                if session.wants(&tweet.tweet) && enqueue(peer, queue, tweet, state).is_err() {
+                    tx_ws
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Again,
+                            reason: "too slow to keep up with tweets".into(),
+                        })))
+                        .await?;
+
+                    break;
+                }
+            }
+
+            res = rx_upstream_status.changed(), if upstream_open => {
+                if res.is_err() {
+                    // the supervisor is gone, rx_tweet will close soon enough
+                    upstream_open = false;
+                    continue;
+                }
+
+                let upstream_status = rx_upstream_status.borrow_and_update().clone();
+                send_message(
+                    &mut tx_ws,
+                    peer.encoding,
+                    &api::ServerMessage::UpstreamStatus(&upstream_status),
+                )
+                .await?;
+            }
+
+            _ = ping_interval.tick() => {
+                // the connection may still be busy, but the client stopped answering pings
+                anyhow::ensure!(
+                    heartbeat.timed_out(pong_timeout).not(),
+                    "no pong received in {:?}",
+                    pong_timeout
+                );
+
+                log::debug!(
+                    client_id = peer.id.0, addr:% = peer.addr;
+                    "pinging {}", peer
+                );
+
+                tx_ws.send(Message::Ping(heartbeat.ping())).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Queues a tweet for the writer, Err when the client is to be disconnected for being too slow
+fn enqueue(
+    peer: &Peer,
+    queue: &Queue<ReceivedTweet>,
+    tweet: ReceivedTweet,
+    state: &State,
+) -> Result<(), ()> {
+    match queue.push(tweet) {
+        Pushed::Queued => Ok(()),
+
+        Pushed::DroppedOldest => {
+            log::debug!(
+                client_id = peer.id.0, addr:% = peer.addr;
+                "queue of {} is full, dropped the oldest tweet", peer
+            );
+            state.metrics.tweets_dropped.inc();
+            Ok(())
+        }
+
+        Pushed::Full => {
+            log::warn!(
+                client_id = peer.id.0, addr:% = peer.addr;
+                "queue of {} is full, disconnecting", peer
+            );
+            state.metrics.slow_client_disconnects.inc();
+            Err(())
+        }
+    }
+}
+
+// Writes what the conversation sends right away, and tweets from the queue in between.
+// Ends once the conversation is over and everything it sent was written.
+async fn writer<S>(
+    mut tx_ws: S,
+    mut rx_control: futures::channel::mpsc::UnboundedReceiver<Message>,
+    queue: &Queue<ReceivedTweet>,
+    peer: &Peer,
+    state: &State,
+) -> Result<()>
+where
+    S: Sink<Message> + Send + Sync + Unpin,
+    <S as Sink<Message>>::Error: 'static + Send + Sync + std::error::Error,
+{
+    loop {
+        tokio::select! {
+            biased;
+
+            msg = rx_control.next() => {
+                let Some(msg) = msg else {
+                    return Ok(());
+                };
+
+                let closing = matches!(msg, Message::Close(_));
+                tx_ws.send(msg).await?;
+
+                // nothing else can be sent after a close frame
+                if closing {
+                    return Ok(());
+                }
+            }
 
-                // send tweets to all clients during debug
-                if cfg!(debug_assertions) || follows.contains(&tweet.user.as_ref().unwrap().id) {
-                    send_json(
+            (dropped, tweet) = queue.pop() => {
+                if dropped > 0 {
+                    send_message(
                         &mut tx_ws,
-                        &api::ServerMessage::Tweet(api::SerializeWrapper(&tweet)),
+                        peer.encoding,
+                        &api::ServerMessage::Dropped { count: dropped },
                     )
                     .await?;
                 }
+
+                send_tweet(&mut tx_ws, peer, &tweet, state).await?;
             }
+        }
+    }
+}
 
-            _ = heartbeat.tick() => {
-                log::debug!("pinging {}", addr);
+async fn send_tweet<S>(
+    tx_ws: &mut S,
+    peer: &Peer,
+    ReceivedTweet { tweet, span }: &ReceivedTweet,
+    state: &State,
+) -> Result<()>
+where
+    S: Sink<Message> + Send + Sync + Unpin,
+    <S as Sink<Message>>::Error: 'static + Send + Sync + std::error::Error,
+{
+    log::debug!(
+        client_id = peer.id.0,
+        addr:% = peer.addr,
+        tweet_id = tweet.id;
+        "sending tweet to {}", peer
+    );
 
-                // TODO: send random data and verify when receiving pongs
-                tx_ws.send(Message::Ping(b"xd".to_vec())).await?;
-            }
+    let span = tracing::info_span!(parent: span, "deliver_tweet");
+    span.follows_from(tracing::Span::current());
+
+    send_message(
+        tx_ws,
+        peer.encoding,
+        &api::ServerMessage::Tweet(api::SerializeWrapper(tweet)),
+    )
+    .instrument(span)
+    .await?;
+
+    state
+        .metrics
+        .tweets_delivered
+        .with_label_values(&[&peer.id.0.to_string()])
+        .inc();
+
+    Ok(())
+}
+
+// Picks up the follows and tweets of a parked session.
+// The follows are requested for this connection before being dropped from the previous one,
+// so that the twitter stream never sees them go away.
+async fn resume<S>(
+    peer: &Peer,
+    session: &mut Session,
+    resumed: Session,
+    tx_ws: &mut S,
+    queue: &Queue<ReceivedTweet>,
+    tx_requested_follows: &mpsc::Sender<(ClientId, Follows)>,
+    state: &State,
+) -> Result<()>
+where
+    S: Sink<Message> + Send + Sync + Unpin,
+    <S as Sink<Message>>::Error: 'static + Send + Sync + std::error::Error,
+{
+    log::info!(
+        client_id = peer.id.0,
+        addr:% = peer.addr,
+        resumed_client_id = resumed.client_id.0,
+        follow_count = resumed.follows.len(),
+        missed_count = resumed.missed.len();
+        "{} resumed the session of {}, {} tweets were missed",
+        peer, resumed.client_id, resumed.missed.len()
+    );
+
+    let Session {
+        client_id,
+        follows,
+        rx_tweet,
+        missed,
+    } = resumed;
+
+    tx_requested_follows
+        .send((peer.id, follows.clone()))
+        .await
+        .context("no rx_requested_follows remaining")?;
+    tx_requested_follows
+        .send((client_id, Follows::new()))
+        .await
+        .context("no rx_requested_follows remaining")?;
+
+    // rx_tweet picks up right after the missed tweets
+    session.follows = follows;
+    session.rx_tweet = rx_tweet;
+    state.client_follows_changed(peer.id, session.follows.len());
+
+    send_message(
+        &mut *tx_ws,
+        peer.encoding,
+        &api::ServerMessage::AckSubscriptions(&session.follows),
+    )
+    .await?;
+
+    for tweet in missed {
+        // with the disconnect policy, what doesn't fit is lost rather than
+        // disconnecting a client that just came back
+        if enqueue(peer, queue, tweet, state).is_err() {
+            break;
         }
     }
 
     Ok(())
 }
 
+#[allow(clippy::too_many_lines, clippy::too_many_arguments)]
 async fn handle_ws_message<S>(
     ws_msg: Message,
-    addr: SocketAddr,
+    peer: &Peer,
     follows: &mut Follows,
+    heartbeat: &mut Heartbeat,
     mut tx_ws: S,
-    tx_requested_follows: &mut mpsc::Sender<(SocketAddr, Follows)>,
+    tx_requested_follows: &mpsc::Sender<(ClientId, Follows)>,
+    state: &State,
+    auth: &auth::Auth,
+    quota: &Quota,
     lifeline: &Arc<Notify>,
 ) -> Result<()>
 where
@@ -163,78 +769,425 @@ where
     <S as Sink<Message>>::Error: 'static + Send + Sync + std::error::Error,
 {
     #[allow(clippy::match_same_arms)]
-    let data = match ws_msg {
-        Message::Text(data) => data,
-
-        Message::Binary(_) => return Ok(()),
+    match &ws_msg {
+        Message::Text(_) | Message::Binary(_) => {}
 
         // handled by tungstenite
         Message::Ping(_) => return Ok(()),
 
         Message::Pong(data) => {
-            anyhow::ensure!(data == b"xd", "invalid pong");
+            // unsolicited pongs and answers to older pings are allowed, but not measured
+            if let Some(rtt) = heartbeat.pong(data) {
+                log::debug!(
+                    client_id = peer.id.0, addr:% = peer.addr, rtt:? = rtt;
+                    "{} answered ping in {:?}", peer, rtt
+                );
+                state.client_rtt_measured(peer.id, rtt);
+            }
+
             return Ok(());
         }
 
         Message::Close(reason) => {
-            log::info!("websocket from {} sent close frame: {:?}", addr, reason);
+            log::info!(
+                client_id = peer.id.0, addr:% = peer.addr;
+                "websocket from {} sent close frame: {:?}", peer, reason
+            );
             tx_ws.send(Message::Close(None)).await?;
             return Ok(());
         }
 
         // not received while reading
         Message::Frame(_) => return Ok(()),
+    }
+
+    let Some((encoding, data)) = frame_data(&ws_msg, peer.encoding) else {
+        return Ok(());
     };
 
-    match serde_json::from_str(&data) {
+    let request = match api::ClientRequest::parse(encoding, data) {
+        Ok(request) => request,
         Err(error) => {
-            log::error!("json parse error: {:#}", error);
+            log::error!(
+                client_id = peer.id.0,
+                addr:% = peer.addr,
+                code:? = error.code,
+                error = error.message;
+                "invalid message from {}: {}", peer, error.message
+            );
+            state.metrics.protocol_errors.inc();
 
-            send_json(
+            let error_message = api::ProtocolError {
+                code: error.code,
+                message: &error.message,
+            };
+            return send_reply(
                 &mut tx_ws,
-                &api::ServerMessage::ProtocolError(&error.to_string()),
+                peer.encoding,
+                api::ServerMessage::ProtocolError(error_message),
+                error.id.as_ref(),
             )
-            .await?;
+            .await;
+        }
+    };
+    let id = request.id.as_ref();
 
+    let new_follows = match request.message {
+        api::ClientMessage::Auth(_) => {
+            // already authenticated, or authentication is disabled
             return Ok(());
         }
 
-        Ok(api::ClientMessage::Exit) => {
-            log::warn!("client {} requested exit", addr);
+        api::ClientMessage::Exit => {
+            // authenticated by now, the handler makes sure of it
+            let role = peer.role.unwrap_or(auth::Role::Subscriber);
+
+            if let Err(reason) = auth.authorize_exit(role) {
+                log::warn!(
+                    client_id = peer.id.0, addr:% = peer.addr;
+                    "client {} was refused exit: {}", peer, reason
+                );
+
+                let error_message = api::ProtocolError {
+                    code: api::ErrorCode::Unauthorized,
+                    message: reason,
+                };
+                return send_reply(
+                    &mut tx_ws,
+                    peer.encoding,
+                    api::ServerMessage::ProtocolError(error_message),
+                    id,
+                )
+                .await;
+            }
+
+            log::warn!(
+                client_id = peer.id.0, addr:% = peer.addr;
+                "client {} requested exit", peer
+            );
             lifeline.notify_one();
             return Ok(());
         }
 
-        Ok(api::ClientMessage::SetSubscriptions(new_follows)) => {
-            *follows = new_follows;
+        api::ClientMessage::SetSubscriptions(new_follows) => new_follows,
+
+        api::ClientMessage::InsertSubscriptions(new_follows) => {
+            follows.union(&new_follows).copied().collect()
         }
 
-        Ok(api::ClientMessage::InsertSubscriptions(new_follows)) => {
-            follows.extend(new_follows);
+        api::ClientMessage::RemoveSubscriptions(new_follows) => {
+            follows.difference(&new_follows).copied().collect()
         }
+    };
+
+    let (granted, refused) = quota.request(follows, new_follows);
+    *follows = granted;
+
+    if refused.is_empty().not() {
+        log::warn!(
+            client_id = peer.id.0, addr:% = peer.addr, refused = refused.len();
+            "refused {} follows from {} over the follow limits", refused.len(), peer
+        );
+        state.metrics.follows_refused.inc_by(refused.len() as u64);
+    }
+
+    log::debug!(
+        client_id = peer.id.0, addr:% = peer.addr, follow_count = follows.len();
+        "{} now follows {} users", peer, follows.len()
+    );
+
+    async {
+        tx_requested_follows
+            .send((peer.id, follows.clone()))
+            .await
+            .context("no rx_requested_follows remaining")?;
+
+        state.client_follows_changed(peer.id, follows.len());
 
-        Ok(api::ClientMessage::RemoveSubscriptions(new_follows)) => {
-            follows.retain(|f| new_follows.contains(f).not());
+        if refused.is_empty() {
+            let message = api::ServerMessage::AckSubscriptions(follows);
+            return send_reply(&mut tx_ws, peer.encoding, message, id).await;
         }
+
+        // the only reply to the request, the rest of which still applies
+        let error_message = api::ProtocolError {
+            code: api::ErrorCode::TooManyFollows,
+            message: &format!("{} follows are over the follow limits", refused.len()),
+        };
+        let reply = api::Reply {
+            message: api::ServerMessage::ProtocolError(error_message),
+            id,
+            refused: Some(&refused),
+        };
+        send_message(&mut tx_ws, peer.encoding, &reply).await
     }
+    .instrument(tracing::info_span!(
+        "follows_update",
+        follow_count = follows.len()
+    ))
+    .await
+}
 
-    tx_requested_follows
-        .send((addr, follows.clone()))
+// Reads the name from either an `X-Client-Name` header or a `name` query parameter,
+// names that are too long or contain control characters are ignored
+pub fn client_name_from_request<B>(request: &http::Request<B>) -> Option<String> {
+    let header = request
+        .headers()
+        .get("x-client-name")
+        .and_then(|value| value.to_str().ok());
+
+    header
+        .map(String::from)
+        .or_else(|| query_param(request, "name"))
+        .filter(|name| {
+            name.is_empty().not()
+                && name.len() <= CLIENT_NAME_MAX_LEN
+                && name.chars().any(char::is_control).not()
+        })
+}
+
+// Reads the token of the session to resume from either an `X-Session-Token` header or a
+// `session` query parameter
+fn resume_token_from_request(request: &Request) -> Option<String> {
+    let header = request
+        .headers()
+        .get("x-session-token")
+        .and_then(|value| value.to_str().ok());
+
+    header
+        .map(String::from)
+        .or_else(|| query_param(request, "session"))
+}
+
+// The encoding and data of a frame that carries a client message.
+// Text frames are always JSON, binary frames are only understood once a binary encoding was
+// negotiated and are ignored otherwise.
+fn frame_data(ws_msg: &Message, encoding: Encoding) -> Option<(Encoding, &[u8])> {
+    match ws_msg {
+        Message::Text(data) => Some((Encoding::Json, data.as_bytes())),
+        Message::Binary(data) if encoding != Encoding::Json => Some((encoding, data)),
+        _ => None,
+    }
+}
+
+// Checks the token given during the handshake, or waits for the client to send one
+async fn authenticate<S>(
+    handshake_token: Option<String>,
+    mut rx_ws: S,
+    auth: &auth::Auth,
+    encoding: Encoding,
+) -> Result<Option<auth::Role>>
+where
+    S: futures::Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    if let Some(token) = handshake_token {
+        return Ok(auth.role(&token));
+    }
+
+    let ws_msg = timeout(WS_AUTH_TIMEOUT, rx_ws.next())
         .await
-        .context("no rx_requested_follows remaining")?;
+        .context("ws connection did not authenticate in time")?
+        .context("ws stream ended")??;
 
-    send_json(&mut tx_ws, &api::ServerMessage::AckSubscriptions(follows)).await?;
+    let Some((encoding, data)) = frame_data(&ws_msg, encoding) else {
+        return Ok(None);
+    };
 
-    Ok(())
+    match api::ClientRequest::parse(encoding, data) {
+        Ok(api::ClientRequest {
+            message: api::ClientMessage::Auth(token),
+            ..
+        }) => Ok(auth.role(&token)),
+        _ => Ok(None),
+    }
 }
 
-async fn send_json<S, Data>(mut tx_ws: S, data: Data) -> Result<()>
+async fn send_message<S, Data>(mut tx_ws: S, encoding: Encoding, data: Data) -> Result<()>
 where
     Data: serde::Serialize + Send + Sync,
     S: Sink<Message> + Send + Sync + Unpin,
     <S as Sink<Message>>::Error: 'static + Send + Sync + std::error::Error,
 {
-    Ok(tx_ws
-        .send(Message::Text(serde_json::to_string(&data)?))
-        .await?)
+    Ok(tx_ws.send(encoding.encode(&data)?).await?)
+}
+
+// Sends a message in reply to the client message with the given id
+async fn send_reply<S>(
+    tx_ws: S,
+    encoding: Encoding,
+    message: api::ServerMessage<'_>,
+    id: Option<&api::RequestId>,
+) -> Result<()>
+where
+    S: Sink<Message> + Send + Sync + Unpin,
+    <S as Sink<Message>>::Error: 'static + Send + Sync + std::error::Error,
+{
+    let reply = api::Reply {
+        message,
+        id,
+        refused: None,
+    };
+    send_message(tx_ws, encoding, &reply).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("pajbot-forsen", "/", Some("pajbot-forsen"))]
+    #[case("", "/?name=pajbot-forsen", Some("pajbot-forsen"))]
+    #[case("", "/?token=hunter2&name=pajbot-forsen", Some("pajbot-forsen"))]
+    #[case("pajbot-forsen", "/?name=pajbot-nymn", Some("pajbot-forsen"))]
+    #[case("", "/?name=pajbot%20forsen", Some("pajbot forsen"))]
+    #[case("", "/?name=pajbot%0Aforsen", None)]
+    #[case("", "/?name=", None)]
+    #[case("", "/?names=pajbot-forsen", None)]
+    #[case("", "/", None)]
+    fn test_client_name_from_request(
+        #[case] header: &str,
+        #[case] uri: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        let mut request = Request::builder().uri(uri);
+        if header.is_empty().not() {
+            request = request.header("X-Client-Name", header);
+        }
+
+        let name = client_name_from_request(&request.body(()).unwrap());
+        assert_eq!(name.as_deref(), expected);
+    }
+
+    #[test]
+    fn test_client_name_too_long() {
+        let uri = format!("/?name={}", "a".repeat(CLIENT_NAME_MAX_LEN + 1));
+        let request = Request::builder().uri(uri).body(()).unwrap();
+
+        assert_eq!(client_name_from_request(&request), None);
+    }
+
+    #[test]
+    fn test_listening() {
+        let state = State::new(watch::channel(api::UpstreamStatus::Stopped).1);
+        state.set_websocket_listening(true);
+
+        let listening = Listening(&state);
+        assert!(state.websocket_listening());
+        drop(listening);
+        assert!(!state.websocket_listening());
+    }
+
+    fn peer(role: Option<auth::Role>) -> Peer {
+        Peer {
+            id: ClientId(0),
+            addr: PeerAddr::Unix,
+            name: None,
+            role,
+            encoding: Encoding::Json,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_too_many_follows() {
+        let peer = peer(None);
+        let mut follows = Follows::new();
+        let (tx_requested_follows, mut rx_requested_follows) = mpsc::channel(1);
+        let state = State::new(watch::channel(api::UpstreamStatus::Stopped).1);
+        let (mut tx_ws, rx_ws) = futures::channel::mpsc::unbounded();
+
+        handle_ws_message(
+            Message::Text(r#"{"type":"set_subscriptions","data":[1,2],"id":1}"#.into()),
+            &peer,
+            &mut follows,
+            &mut Heartbeat::default(),
+            &mut tx_ws,
+            &tx_requested_follows,
+            &state,
+            &auth::Auth::default(),
+            &Quota::new(1, 10),
+            &Arc::new(Notify::new()),
+        )
+        .await
+        .unwrap();
+        drop(tx_ws);
+
+        // the follows under the limits are still granted
+        assert_eq!(follows, Follows::from([1]));
+        assert_eq!(rx_requested_follows.recv().await.unwrap().1, follows);
+
+        let replies: Vec<_> = rx_ws
+            .map(|message| message.into_text().unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            replies,
+            [
+                r#"{"type":"protocol_error","data":{"code":"too_many_follows","message":"1 follows are over the follow limits"},"id":1,"refused":[2]}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disconnected_closed() {
+        let peer = peer(None);
+        let state = State::new(watch::channel(api::UpstreamStatus::Stopped).1);
+        state.client_connected(peer.id, peer.addr);
+        let (tx_requested_follows, mut rx_requested_follows) = mpsc::channel(1);
+        let (_tx_tweet, rx_tweet) = broadcast::channel(1);
+        let quota = Arc::new(Quota::new(1, 1));
+
+        let mut session = Session::new(peer.id, rx_tweet);
+        (session.follows, _) = quota.request(&Follows::new(), Follows::from([1]));
+
+        // going away without a close frame still lets go of the follows
+        disconnected(
+            Err(WsError::ConnectionClosed.into()),
+            &peer,
+            session,
+            None,
+            tx_requested_follows,
+            &state,
+            &Arc::new(Sessions::new(Duration::ZERO)),
+            quota.clone(),
+        )
+        .await;
+
+        assert_eq!(
+            rx_requested_follows.recv().await.unwrap(),
+            (peer.id, Follows::new())
+        );
+        assert!(state.clients().is_empty());
+        let (_, refused) = quota.request(&Follows::new(), Follows::from([2]));
+        assert!(refused.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disconnected_parked() {
+        let peer = peer(Some(auth::Role::Subscriber));
+        let state = State::new(watch::channel(api::UpstreamStatus::Stopped).1);
+        let (tx_requested_follows, mut rx_requested_follows) = mpsc::channel(1);
+        let (_tx_tweet, rx_tweet) = broadcast::channel(1);
+        let sessions = Arc::new(Sessions::new(Duration::from_secs(30)));
+
+        let mut session = Session::new(peer.id, rx_tweet);
+        session.follows.insert(1);
+
+        disconnected(
+            Err(WsError::ConnectionClosed.into()),
+            &peer,
+            session,
+            Some("hunter2".into()),
+            tx_requested_follows,
+            &state,
+            &sessions,
+            Arc::new(Quota::new(10, 10)),
+        )
+        .await;
+
+        // still subscribed, until the session expires
+        assert!(rx_requested_follows.try_recv().is_err());
+        let session = sessions.resume("hunter2").await.unwrap();
+        assert_eq!(session.follows, Follows::from([1]));
+    }
 }